@@ -1,3 +1,7 @@
+use super::audio::sampler::{Sample, SampleLoader};
+use super::instrument::Instruments;
+use super::song::Song;
+use super::song_file::SongLoader;
 use super::GameState;
 use bevy::prelude::*;
 
@@ -6,7 +10,15 @@ pub struct AssetPlugin;
 impl bevy::app::Plugin for AssetPlugin {
     fn build(&self, app: &mut App) {
         app.insert_resource(Sprites::default())
-            .add_system(load_assets.in_schedule(OnEnter(GameState::Menu)));
+            .init_resource::<Songs>()
+            .init_resource::<Instruments>()
+            .add_asset::<Song>()
+            .init_asset_loader::<SongLoader>()
+            .init_resource::<Samples>()
+            .add_asset::<Sample>()
+            .init_asset_loader::<SampleLoader>()
+            .add_system(load_assets.in_schedule(OnEnter(GameState::Menu)))
+            .add_system(sample_library_system);
     }
 }
 
@@ -22,11 +34,34 @@ pub struct Sprites {
     pub shot: Handle<TextureAtlas>,
 }
 
+#[derive(Resource, Default)]
+pub struct Songs {
+    pub mary: Handle<Song>,
+    pub techno: Handle<Song>,
+    /// The song being played, reloaded when its file changes
+    pub current: Handle<Song>,
+}
+
+/// Everything in `assets/samples`, kept loaded for `Instrument::Sampler`
+#[derive(Resource, Default)]
+pub struct Samples {
+    pub handles: Vec<HandleUntyped>,
+}
+
 fn load_assets(
     asset_server: Res<AssetServer>,
     mut texture_atlases: ResMut<Assets<TextureAtlas>>,
     mut sprites: ResMut<Sprites>,
+    mut songs: ResMut<Songs>,
+    mut samples: ResMut<Samples>,
 ) {
+    songs.mary = asset_server.load("songs/mary.bjsong");
+    songs.techno = asset_server.load("songs/techno.bjsong");
+    match asset_server.load_folder("samples") {
+        Ok(handles) => samples.handles = handles,
+        Err(err) => warn!("Couldn't load samples: {}", err),
+    }
+
     sprites.player = texture_atlases.add(TextureAtlas::from_grid(
         asset_server.load("sprites/player.png"),
         Vec2::new(16.0, 16.0),
@@ -92,3 +127,24 @@ fn load_assets(
         None,
     ));
 }
+
+/// Hand loaded (or changed) samples to the instruments, by asset path
+fn sample_library_system(
+    mut events: EventReader<AssetEvent<Sample>>,
+    samples: Res<Assets<Sample>>,
+    asset_server: Res<AssetServer>,
+    instruments: Res<Instruments>,
+) {
+    for event in events.iter() {
+        let (AssetEvent::Created { handle } | AssetEvent::Modified { handle }) = event else {
+            continue;
+        };
+        let (Some(sample), Some(path)) =
+            (samples.get(handle), asset_server.get_handle_path(handle))
+        else {
+            continue;
+        };
+        let path = path.path().to_string_lossy().replace('\\', "/");
+        instruments.samples().insert(&path, sample.clone());
+    }
+}
@@ -0,0 +1,266 @@
+//! Levels and spectrum of what's being played, for gameplay and visuals to react to.
+
+use super::audio_generator::*;
+use super::audio_output::AudioOutput;
+use super::mixer::Bus;
+use bevy::prelude::*;
+use std::f32::consts::PI;
+
+/// Bands in `Analysis::spectrum`, spaced evenly in octaves
+pub const SPECTRUM_BANDS: usize = 16;
+/// Lowest frequency in the spectrum, in hz
+const SPECTRUM_MIN: f32 = 40.0;
+/// Samples of the master mix in each FFT, a power of 2
+const FFT_SIZE: usize = 1024;
+/// Reads in a row with nothing rendered before the levels fall silent, eg: while paused.
+/// The device pulls audio in bursts, so a frame or two without any is normal.
+const SILENT_READS: u32 = 4;
+
+/// Level of a signal over the last frame, from 0.0 to 1.0 for a full scale signal
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Levels {
+    pub rms: f32,
+    pub peak: f32,
+}
+
+/// What's audible, updated every frame by `analysis_system`.
+#[derive(Resource, Clone, Debug, Default)]
+pub struct Analysis {
+    /// By `Song` track
+    pub tracks: Vec<Levels>,
+    pub sfx: Levels,
+    /// After the send effects and master gain
+    pub master: Levels,
+    /// Magnitude of each band of the master mix, low to high, 1.0 for a full scale sine
+    pub spectrum: [f32; SPECTRUM_BANDS],
+}
+
+impl Analysis {
+    /// Silent for tracks that haven't played
+    pub fn track(&self, track: usize) -> Levels {
+        self.tracks.get(track).copied().unwrap_or_default()
+    }
+
+    /// Centre frequency of a `spectrum` band, in hz
+    pub fn band_frequency(band: usize) -> f32 {
+        SPECTRUM_MIN * band_ratio().powf(band as f32 + 0.5)
+    }
+}
+
+/// Ratio between the edges of a band, so the bands reach up to nyquist
+fn band_ratio() -> f32 {
+    (sample_rate() * 0.5 / SPECTRUM_MIN).powf(1.0 / SPECTRUM_BANDS as f32)
+}
+
+/// Sum of squares and peak of a signal, since it was last read
+#[derive(Clone, Copy, Default)]
+struct LevelMeter {
+    sum_squares: f32,
+    peak: f32,
+    count: usize,
+}
+
+impl LevelMeter {
+    fn add(&mut self, sample: f32) {
+        self.sum_squares += sample * sample;
+        self.peak = self.peak.max(sample.abs());
+        self.count += 1;
+    }
+
+    fn merge(&mut self, other: &LevelMeter) {
+        self.sum_squares += other.sum_squares;
+        self.peak = self.peak.max(other.peak);
+        self.count += other.count;
+    }
+
+    /// `None` if nothing has been added since the last read
+    fn take(&mut self) -> Option<Levels> {
+        let meter = std::mem::take(self);
+        (meter.count > 0).then(|| Levels {
+            rms: (meter.sum_squares / meter.count as f32).sqrt(),
+            peak: meter.peak,
+        })
+    }
+}
+
+/// Levels of each bus, and the latest master samples.
+/// Filled in by the mixer as it renders, and read once a frame.
+pub struct Meter {
+    tracks: Vec<LevelMeter>,
+    sfx: LevelMeter,
+    master: LevelMeter,
+    /// Last `FFT_SIZE` master samples, mono, oldest at `history_position`
+    history: Vec<f32>,
+    history_position: usize,
+    /// Reads in a row that had nothing rendered
+    empty_reads: u32,
+}
+
+impl Default for Meter {
+    fn default() -> Self {
+        Meter {
+            tracks: Vec::new(),
+            sfx: LevelMeter::default(),
+            master: LevelMeter::default(),
+            history: vec![0.; FFT_SIZE],
+            history_position: 0,
+            empty_reads: 0,
+        }
+    }
+}
+
+impl Meter {
+    /// A frame of a bus, after its gain
+    pub(super) fn add(&mut self, bus: Bus, sample: f32) {
+        match bus {
+            Bus::Track(track) => {
+                if track >= self.tracks.len() {
+                    self.tracks.resize(track + 1, LevelMeter::default());
+                }
+                self.tracks[track].add(sample);
+            }
+            Bus::Sfx => self.sfx.add(sample),
+        }
+    }
+
+    /// A frame of the master mix
+    pub(super) fn add_master(&mut self, left: f32, right: f32) {
+        let mono = (left + right) * 0.5;
+        self.master.add(mono);
+        self.history[self.history_position] = mono;
+        self.history_position = (self.history_position + 1) % FFT_SIZE;
+    }
+
+    /// Move everything measured from `other` into this meter, eg: once per rendered block
+    pub(super) fn merge(&mut self, other: &mut Meter) {
+        if other.tracks.len() > self.tracks.len() {
+            self.tracks
+                .resize(other.tracks.len(), LevelMeter::default());
+        }
+        for (track, other_track) in self.tracks.iter_mut().zip(&other.tracks) {
+            track.merge(other_track);
+        }
+        self.sfx.merge(&other.sfx);
+        self.master.merge(&other.master);
+
+        // Only the newest samples are kept
+        let new = other.master.count.min(FFT_SIZE);
+        for idx in 0..new {
+            let from = (other.history_position + FFT_SIZE - new + idx) % FFT_SIZE;
+            self.history[self.history_position] = other.history[from];
+            self.history_position = (self.history_position + 1) % FFT_SIZE;
+        }
+
+        other
+            .tracks
+            .iter_mut()
+            .for_each(|track| *track = LevelMeter::default());
+        other.sfx = LevelMeter::default();
+        other.master = LevelMeter::default();
+    }
+
+    /// Update `analysis` with everything measured since the last read.
+    /// Levels are held briefly if no audio has been rendered since, then fall to silence.
+    pub fn read(&mut self, analysis: &mut Analysis) {
+        if self.master.count == 0 {
+            self.empty_reads += 1;
+            if self.empty_reads >= SILENT_READS {
+                analysis.tracks.fill(Levels::default());
+                analysis.sfx = Levels::default();
+                analysis.master = Levels::default();
+                analysis.spectrum = [0.; SPECTRUM_BANDS];
+            }
+            return;
+        }
+        self.empty_reads = 0;
+
+        if analysis.tracks.len() < self.tracks.len() {
+            analysis.tracks.resize(self.tracks.len(), Levels::default());
+        }
+        for (levels, meter) in analysis.tracks.iter_mut().zip(&mut self.tracks) {
+            if let Some(new) = meter.take() {
+                *levels = new;
+            }
+        }
+        if let Some(new) = self.sfx.take() {
+            analysis.sfx = new;
+        }
+        if let Some(new) = self.master.take() {
+            analysis.master = new;
+            analysis.spectrum = self.spectrum();
+        }
+    }
+
+    fn spectrum(&self) -> [f32; SPECTRUM_BANDS] {
+        // Hann windowed, oldest sample first
+        let mut real: Vec<f32> = (0..FFT_SIZE)
+            .map(|idx| {
+                let sample = self.history[(self.history_position + idx) % FFT_SIZE];
+                let window = 0.5 - 0.5 * (2. * PI * idx as f32 / FFT_SIZE as f32).cos();
+                sample * window
+            })
+            .collect();
+        let mut imag = vec![0.; FFT_SIZE];
+        fft(&mut real, &mut imag);
+
+        // A full scale sine peaks at N / 4 once windowed
+        let scale = 4. / FFT_SIZE as f32;
+        let bin_width = sample_rate() / FFT_SIZE as f32;
+        let ratio = band_ratio();
+        let mut bands = [0.0_f32; SPECTRUM_BANDS];
+        for (bin, (re, im)) in real.iter().zip(&imag).enumerate().take(FFT_SIZE / 2) {
+            let frequency = bin as f32 * bin_width;
+            if frequency < SPECTRUM_MIN {
+                continue;
+            }
+            let band = ((frequency / SPECTRUM_MIN).ln() / ratio.ln()) as usize;
+            let magnitude = (re * re + im * im).sqrt() * scale;
+            if let Some(level) = bands.get_mut(band) {
+                *level = level.max(magnitude);
+            }
+        }
+        bands
+    }
+}
+
+/// In place radix 2 FFT, `real` and `imag` must be the same power of 2 length
+fn fft(real: &mut [f32], imag: &mut [f32]) {
+    let n = real.len();
+
+    // Bit reversed order
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            real.swap(i, j);
+            imag.swap(i, j);
+        }
+    }
+
+    let mut length = 2;
+    while length <= n {
+        let angle = -2. * PI / length as f32;
+        for start in (0..n).step_by(length) {
+            for k in 0..length / 2 {
+                let (sin, cos) = (angle * k as f32).sin_cos();
+                let (a, b) = (start + k, start + k + length / 2);
+                let re = real[b] * cos - imag[b] * sin;
+                let im = real[b] * sin + imag[b] * cos;
+                real[b] = real[a] - re;
+                imag[b] = imag[a] - im;
+                real[a] += re;
+                imag[a] += im;
+            }
+        }
+        length <<= 1;
+    }
+}
+
+pub fn analysis_system(audio_output: Res<AudioOutput>, mut analysis: ResMut<Analysis>) {
+    audio_output.mixer.analyse(&mut analysis);
+}
@@ -1,26 +1,113 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use rodio::source::Source;
+use serde::Deserialize;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
-/// Single channel audio generator
-pub trait GenSource: Iterator<Item = f32> + Send + Sync + 'static {}
+/// Samples rendered at a time by generators that need scratch space, kept on the stack.
+pub const BLOCK_SIZE: usize = 64;
+
+/// Single channel audio generator.
+/// Rendered a block at a time with `fill`, `Iterator::next` is kept for a sample at a time.
+pub trait GenSource: Iterator<Item = f32> + Send + Sync + 'static {
+    /// Render the next samples into `out`, interleaved if stereo.
+    /// Returns how many were rendered, fewer than `out.len()` once the source has ended,
+    /// when the rest of `out` is left unspecified.
+    fn fill(&mut self, out: &mut [f32]) -> usize {
+        for (idx, sample) in out.iter_mut().enumerate() {
+            match self.next() {
+                Some(next) => *sample = next,
+                None => return idx,
+            }
+        }
+        out.len()
+    }
+
+    /// Restart from how it was built, to play the same note again without building it again.
+    /// `false` if it can't, then it's left part way and needs building again.
+    fn retrigger(&mut self) -> bool {
+        false
+    }
+}
+
+/// `Iterator::next` of a source that renders with `fill`
+pub fn next_sample<T>(source: &mut T) -> Option<f32>
+where
+    T: GenSource + ?Sized,
+{
+    let mut sample = [0.];
+    (source.fill(&mut sample) == 1).then_some(sample[0])
+}
+
+/// Fill `out` at most `BLOCK_SIZE` samples at a time, until `fill_block` renders a short block
+pub fn fill_blocks<F>(out: &mut [f32], mut fill_block: F) -> usize
+where
+    F: FnMut(&mut [f32]) -> usize,
+{
+    let mut rendered = 0;
+    for block in out.chunks_mut(BLOCK_SIZE) {
+        let len = fill_block(block);
+        rendered += len;
+        if len < block.len() {
+            break;
+        }
+    }
+    rendered
+}
 
 /// Type-erased wrapper around a source.
-/// Has 1 channel, sample_rate of 44100
+/// Has 1 channel, or 2 interleaved (left, right) when stereo, sample_rate of `sample_rate()`
 pub struct RawSource {
     source: Box<dyn GenSource>,
+    channels: u16,
+    /// Built again rather than retriggered
+    once: bool,
 }
 
-impl GenSource for RawSource {}
+impl GenSource for RawSource {
+    fn fill(&mut self, out: &mut [f32]) -> usize {
+        self.source.fill(out)
+    }
+
+    fn retrigger(&mut self) -> bool {
+        !self.once && self.source.retrigger()
+    }
+}
 
 impl RawSource {
     pub fn new<T>(source: T) -> RawSource
+    where
+        T: GenSource,
+    {
+        Self::with_channels(source, 1)
+    }
+
+    /// `source` interleaves left and right samples
+    pub fn stereo<T>(source: T) -> RawSource
+    where
+        T: GenSource,
+    {
+        Self::with_channels(source, 2)
+    }
+
+    pub fn with_channels<T>(source: T, channels: u16) -> RawSource
     where
         T: GenSource,
     {
         RawSource {
             source: Box::new(source),
+            channels,
+            once: false,
         }
     }
+
+    /// Never retriggered, eg: a placeholder that's built differently once it can be
+    pub fn once(mut self) -> Self {
+        self.once = true;
+        self
+    }
 }
 
 impl Source for RawSource {
@@ -29,11 +116,11 @@ impl Source for RawSource {
     }
 
     fn channels(&self) -> u16 {
-        1
+        self.channels
     }
 
     fn sample_rate(&self) -> u32 {
-        44100
+        sample_rate() as u32
     }
 
     fn total_duration(&self) -> Option<Duration> {
@@ -56,12 +143,114 @@ where
     RawSource::new(source)
 }
 
-const SAMPLE_RATE: f32 = 44100.0;
+/// Rate generators render at until `set_sample_rate` is called, in hz
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+
+static SAMPLE_RATE: AtomicU32 = AtomicU32::new(DEFAULT_SAMPLE_RATE);
+
+/// Rate every generator renders at, in hz
+pub fn sample_rate() -> f32 {
+    SAMPLE_RATE.load(Ordering::Relaxed) as f32
+}
+
+/// Change the rate generators render at, eg: to the output device's.
+/// Set it before creating sources, delays and the like are sized when they're created.
+pub fn set_sample_rate(rate: u32) {
+    SAMPLE_RATE.store(rate.max(1), Ordering::Relaxed);
+}
 
 pub trait Oscillator: GenSource {
     fn set_frequency(&mut self, frequency: f32);
 }
 
+impl GenSource for Box<dyn Oscillator> {
+    fn fill(&mut self, out: &mut [f32]) -> usize {
+        (**self).fill(out)
+    }
+
+    fn retrigger(&mut self) -> bool {
+        (**self).retrigger()
+    }
+}
+
+impl Oscillator for Box<dyn Oscillator> {
+    fn set_frequency(&mut self, frequency: f32) {
+        (**self).set_frequency(frequency);
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub enum Waveform {
+    Square,
+    Saw,
+    Ramp,
+    Triangle,
+    SuperSaw,
+    /// Game Boy style `NoiseLFSR`
+    Noise,
+    /// `NoiseLFSR` in 7 bit mode, metallic
+    ShortNoise,
+    WhiteNoise,
+    PinkNoise,
+    BrownNoise,
+    /// `KarplusStrong` string, plucked when the note starts
+    Pluck,
+    /// `Modal` wooden bar, struck when the note starts
+    Bar,
+    /// `Modal` glass, struck when the note starts
+    Glass,
+}
+
+impl Waveform {
+    /// `band_limited` is ignored by waveforms that have no band limited version
+    pub fn oscillator(&self, frequency: f32, band_limited: bool) -> Box<dyn Oscillator> {
+        match self {
+            Waveform::Square if band_limited => Box::new(SquareWave::new(frequency).band_limited()),
+            Waveform::Square => Box::new(SquareWave::new(frequency)),
+            Waveform::Saw if band_limited => Box::new(SawWave::new(frequency).band_limited()),
+            Waveform::Saw => Box::new(SawWave::new(frequency)),
+            Waveform::Ramp if band_limited => Box::new(RampWave::new(frequency).band_limited()),
+            Waveform::Ramp => Box::new(RampWave::new(frequency)),
+            Waveform::Triangle => Box::new(TriangleWave::new(frequency)),
+            Waveform::SuperSaw if band_limited => Box::new(SuperSaw::new(frequency).band_limited()),
+            Waveform::SuperSaw => Box::new(SuperSaw::new(frequency)),
+            Waveform::Noise => Box::new(NoiseLFSR::new(frequency)),
+            Waveform::ShortNoise => Box::new(NoiseLFSR::new(frequency).short()),
+            Waveform::WhiteNoise => Box::new(Noise::new(NoiseColour::White)),
+            Waveform::PinkNoise => Box::new(Noise::new(NoiseColour::Pink)),
+            Waveform::BrownNoise => Box::new(Noise::new(NoiseColour::Brown)),
+            Waveform::Pluck | Waveform::Bar | Waveform::Glass => self.excited(frequency, 1.0, None),
+        }
+    }
+
+    /// `Pluck`, `Bar` and `Glass` plucked or struck at `velocity`, with `excitation` fed in as they play.
+    /// Other waveforms are as `oscillator`, not band limited.
+    pub fn excited(
+        &self,
+        frequency: f32,
+        velocity: f32,
+        excitation: Option<RawSource>,
+    ) -> Box<dyn Oscillator> {
+        let modes: &[Mode] = match self {
+            Waveform::Pluck => {
+                let mut string = KarplusStrong::new(frequency, 2.0, 0.5).plucked(velocity);
+                if let Some(excitation) = excitation {
+                    string = string.with_excitation(excitation);
+                }
+                return Box::new(string);
+            }
+            Waveform::Bar => &BAR_MODES,
+            Waveform::Glass => &GLASS_MODES,
+            waveform => return waveform.oscillator(frequency, false),
+        };
+        let mut modal = Modal::new(frequency, modes).struck(velocity);
+        if let Some(excitation) = excitation {
+            modal = modal.with_excitation(excitation);
+        }
+        Box::new(modal)
+    }
+}
+
 /// Frequency of C2 in hz. Base for 0.1 / octave (1v / octave, with -1.0 to 1.0 being -10 to 10).
 const C2: f32 = 65.41;
 /// Convert a "voltage" to a frequency
@@ -76,8 +265,25 @@ pub fn volts_per_frequency(frequency: f32) -> f32 {
     (frequency / C2).log2() / 10.0
 }
 
+/// From [PolyBLEP Oscillator](https://www.martin-finke.de/articles/audio-plugins-018-polyblep-oscillator/)
+/// Residual to smooth a discontinuity at period 0.0, for a jump of -2.0.
+/// `period` is 0.0..1.0, `p_step` is the period advanced per sample.
+/// Scale by `jump / 2.` and add to the naive sample.
+fn poly_blep(period: f32, p_step: f32) -> f32 {
+    if period < p_step {
+        let t = period / p_step;
+        t + t - t * t - 1.0
+    } else if period > 1.0 - p_step {
+        let t = (period - 1.0) / p_step;
+        t * t + t + t + 1.0
+    } else {
+        0.0
+    }
+}
+
 pub struct Vco<T: Oscillator, CV: GenSource> {
     oscillator: T,
+    base_frequency: f32,
     base_voltage: f32,
     cv: Option<CV>,
     last_cv: f32,
@@ -88,6 +294,19 @@ where
     T: Oscillator,
     CV: GenSource,
 {
+    fn fill(&mut self, out: &mut [f32]) -> usize {
+        if self.cv.is_none() {
+            return self.oscillator.fill(out);
+        }
+        fill_blocks(out, |block| self.fill_block(block))
+    }
+
+    fn retrigger(&mut self) -> bool {
+        // Retuned first, strings restart their pluck at the frequency they're at
+        self.oscillator.set_frequency(self.base_frequency);
+        self.last_cv = 0.0;
+        self.oscillator.retrigger() && self.cv.as_mut().is_none_or(GenSource::retrigger)
+    }
 }
 
 impl<T, CV> Vco<T, CV>
@@ -105,15 +324,47 @@ where
 
         Vco {
             oscillator,
+            base_frequency,
             base_voltage,
             cv,
             last_cv: 0.0,
         }
     }
 
-    pub fn as_raw(self) -> RawSource {
+    pub fn into_raw(self) -> RawSource {
         RawSource::new(self)
     }
+
+    /// The oscillator is rendered in runs where the CV holds steady
+    fn fill_block(&mut self, out: &mut [f32]) -> usize {
+        let mut voltages = [0.; BLOCK_SIZE];
+        let cv_len = match &mut self.cv {
+            Some(cv) => cv.fill(&mut voltages[..out.len()]),
+            None => 0,
+        };
+
+        let mut start = 0;
+        while start < out.len() {
+            // Keeps its last frequency once the CV ends
+            if start < cv_len && voltages[start] != self.last_cv {
+                self.last_cv = voltages[start];
+                let frequency =
+                    frequency_per_volt((self.last_cv + self.base_voltage).clamp(-1., 1.));
+                self.oscillator.set_frequency(frequency);
+            }
+            let mut end = start + 1;
+            while end < out.len() && (end >= cv_len || voltages[end] == self.last_cv) {
+                end += 1;
+            }
+
+            let len = self.oscillator.fill(&mut out[start..end]);
+            if len < end - start {
+                return start + len;
+            }
+            start = end;
+        }
+        out.len()
+    }
 }
 
 impl<T> Vco<T, RawSource>
@@ -133,27 +384,66 @@ where
     type Item = f32;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if let Some(cv) = &mut self.cv {
-            if let Some(voltage) = cv.next() {
-                if voltage != self.last_cv {
-                    self.last_cv = voltage;
-                    let frequency =
-                        frequency_per_volt((voltage + self.base_voltage).clamp(-1., 1.));
-                    self.oscillator.set_frequency(frequency);
-                }
-            }
+        next_sample(self)
+    }
+}
+
+/// Game Boy pulse channel duty cycles
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+pub enum Duty {
+    /// 12.5%
+    Eighth,
+    /// 25%
+    Quarter,
+    /// 50%, a square wave
+    #[default]
+    Half,
+    /// 75%
+    ThreeQuarters,
+}
+
+impl Duty {
+    /// Fraction of each period spent high
+    pub fn fraction(&self) -> f32 {
+        match self {
+            Duty::Eighth => 0.125,
+            Duty::Quarter => 0.25,
+            Duty::Half => 0.5,
+            Duty::ThreeQuarters => 0.75,
         }
-        self.oscillator.next()
     }
 }
 
+/// Narrowest and widest a pulse can be modulated to, so it never goes silent
+const MIN_DUTY: f32 = 0.01;
+const MAX_DUTY: f32 = 0.99;
+
+/// Pulse wave, a square wave at the default 50% duty cycle
 pub struct SquareWave {
     /// Frequence of the square wave, in Hz
     frequency: f32,
     period: f32,
+    /// Smooth the edges with PolyBLEP to reduce aliasing
+    band_limited: bool,
+    /// Fraction of each period spent high
+    duty: f32,
+    /// Added to the duty, for pulse width modulation
+    duty_cv: Option<RawSource>,
+    /// Last duty the CV gave, held once the CV ends
+    last_duty_cv: f32,
 }
 
-impl GenSource for SquareWave {}
+impl GenSource for SquareWave {
+    fn fill(&mut self, out: &mut [f32]) -> usize {
+        fill_blocks(out, |block| self.fill_block(block))
+    }
+
+    fn retrigger(&mut self) -> bool {
+        self.period = 0.;
+        self.last_duty_cv = 0.;
+        self.duty_cv.as_mut().is_none_or(GenSource::retrigger)
+    }
+}
 
 impl Oscillator for SquareWave {
     fn set_frequency(&mut self, frequency: f32) {
@@ -166,27 +456,45 @@ impl SquareWave {
         SquareWave {
             frequency,
             period: 0.,
+            band_limited: false,
+            duty: 0.5,
+            duty_cv: None,
+            last_duty_cv: 0.,
         }
     }
 
-    pub fn as_raw(self) -> RawSource {
-        RawSource::new(self)
+    /// Anti-aliased (PolyBLEP) version of this oscillator
+    pub fn band_limited(mut self) -> Self {
+        self.band_limited = true;
+        self
     }
-}
 
-impl Default for SquareWave {
-    fn default() -> Self {
-        SquareWave {
-            frequency: 440.,
-            period: 0.,
-        }
+    /// Fraction of each period spent high, eg: `Duty::Eighth.fraction()`
+    pub fn with_duty(mut self, duty: f32) -> Self {
+        self.duty = duty.clamp(MIN_DUTY, MAX_DUTY);
+        self
     }
-}
 
-impl Iterator for SquareWave {
-    type Item = f32;
+    /// Added to the duty each sample, eg: an LFO for smooth PWM
+    pub fn with_duty_cv<T>(mut self, cv: T) -> Self
+    where
+        T: GenSource,
+    {
+        self.duty_cv = Some(RawSource::new(cv));
+        self
+    }
+
+    pub fn into_raw(self) -> RawSource {
+        RawSource::new(self)
+    }
+
+    fn fill_block(&mut self, out: &mut [f32]) -> usize {
+        let mut duty_cv = [0.; BLOCK_SIZE];
+        let cv_len = match &mut self.duty_cv {
+            Some(cv) => cv.fill(&mut duty_cv[..out.len()]),
+            None => 0,
+        };
 
-    fn next(&mut self) -> Option<Self::Item> {
         // Calculate period for frequency and sample rate
         // period = 1sec / frequency, or sample_rate / frequency (for 44100, 221 = 200)
         // from that, get step length (to scale internal period from 0.0 to 1.0)
@@ -196,37 +504,94 @@ impl Iterator for SquareWave {
         //
         // so each sample requested, adjust period by 1 / sample_rate / frequency
         // or: p_step = frequency / sample_rate
-        let p_step = self.frequency / SAMPLE_RATE;
+        let p_step = self.frequency / sample_rate();
+        for (idx, sample) in out.iter_mut().enumerate() {
+            self.period += p_step;
+            if self.period > 1.0 {
+                self.period -= 1.0;
+            }
 
-        self.period += p_step;
-        if self.period > 1.0 {
-            self.period -= 1.0;
-        }
+            let mut duty = self.duty;
+            if self.duty_cv.is_some() {
+                if idx < cv_len {
+                    self.last_duty_cv = duty_cv[idx];
+                }
+                duty = (duty + self.last_duty_cv).clamp(MIN_DUTY, MAX_DUTY);
+            }
 
-        if self.period < 0.5 {
-            Some(0.5)
-        } else {
-            Some(-0.5)
+            *sample = if self.period < duty { 0.5 } else { -0.5 };
+            if self.band_limited {
+                // Rising edge at 0.0, falling edge at the duty
+                *sample += 0.5 * poly_blep(self.period, p_step);
+                *sample -= 0.5 * poly_blep((self.period + (1.0 - duty)) % 1.0, p_step);
+            }
         }
+        out.len()
+    }
+}
+
+impl Default for SquareWave {
+    fn default() -> Self {
+        SquareWave::new(440.)
+    }
+}
+
+impl Iterator for SquareWave {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        next_sample(self)
     }
 }
 
 pub struct SawWave {
     frequency: f32,
     period: f32,
+    /// Smooth the reset with PolyBLEP to reduce aliasing
+    band_limited: bool,
 }
 
-impl GenSource for SawWave {}
+impl GenSource for SawWave {
+    fn fill(&mut self, out: &mut [f32]) -> usize {
+        let p_step = self.frequency / sample_rate();
+        for sample in out.iter_mut() {
+            self.period += p_step;
+            if self.period > 1.0 {
+                self.period -= 1.0;
+            }
+
+            // Goes from 0.5 to -0.5 linearly
+            *sample = (1.0 - self.period) - 0.5;
+            if self.band_limited {
+                // Jumps from -0.5 to 0.5 on reset
+                *sample += 0.5 * poly_blep(self.period, p_step);
+            }
+        }
+        out.len()
+    }
+
+    fn retrigger(&mut self) -> bool {
+        self.period = 0.;
+        true
+    }
+}
 
 impl SawWave {
     pub fn new(frequency: f32) -> Self {
         Self {
             frequency,
             period: 0.,
+            band_limited: false,
         }
     }
 
-    pub fn as_raw(self) -> RawSource {
+    /// Anti-aliased (PolyBLEP) version of this oscillator
+    pub fn band_limited(mut self) -> Self {
+        self.band_limited = true;
+        self
+    }
+
+    pub fn into_raw(self) -> RawSource {
         RawSource::new(self)
     }
 }
@@ -241,34 +606,58 @@ impl Iterator for SawWave {
     type Item = f32;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let p_step = self.frequency / SAMPLE_RATE;
-
-        self.period += p_step;
-        if self.period > 1.0 {
-            self.period -= 1.0;
-        }
-
-        // Goes from 0.5 to -0.5 linearly
-        Some((1.0 - self.period) - 0.5)
+        next_sample(self)
     }
 }
 
 pub struct RampWave {
     frequency: f32,
     period: f32,
+    /// Smooth the reset with PolyBLEP to reduce aliasing
+    band_limited: bool,
 }
 
-impl GenSource for RampWave {}
+impl GenSource for RampWave {
+    fn fill(&mut self, out: &mut [f32]) -> usize {
+        let p_step = self.frequency / sample_rate();
+        for sample in out.iter_mut() {
+            self.period += p_step;
+            if self.period > 1.0 {
+                self.period -= 1.0;
+            }
+
+            // Goes from -0.5 to 0.5 linearly
+            *sample = self.period - 0.5;
+            if self.band_limited {
+                // Jumps from 0.5 to -0.5 on reset
+                *sample -= 0.5 * poly_blep(self.period, p_step);
+            }
+        }
+        out.len()
+    }
+
+    fn retrigger(&mut self) -> bool {
+        self.period = 0.;
+        true
+    }
+}
 
 impl RampWave {
     pub fn new(frequency: f32) -> Self {
         Self {
             frequency,
             period: 0.,
+            band_limited: false,
         }
     }
 
-    pub fn as_raw(self) -> RawSource {
+    /// Anti-aliased (PolyBLEP) version of this oscillator
+    pub fn band_limited(mut self) -> Self {
+        self.band_limited = true;
+        self
+    }
+
+    pub fn into_raw(self) -> RawSource {
         RawSource::new(self)
     }
 }
@@ -283,15 +672,7 @@ impl Iterator for RampWave {
     type Item = f32;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let p_step = self.frequency / SAMPLE_RATE;
-
-        self.period += p_step;
-        if self.period > 1.0 {
-            self.period -= 1.0;
-        }
-
-        // Goes from -0.5 to 0.5 linearly
-        Some(self.period - 0.5)
+        next_sample(self)
     }
 }
 
@@ -300,7 +681,34 @@ pub struct TriangleWave {
     period: f32,
 }
 
-impl GenSource for TriangleWave {}
+impl GenSource for TriangleWave {
+    fn fill(&mut self, out: &mut [f32]) -> usize {
+        let p_step = self.frequency / sample_rate();
+        for sample in out.iter_mut() {
+            self.period += p_step;
+            if self.period > 1.0 {
+                self.period -= 1.0;
+            }
+
+            // low to high to low
+            // 0..0.5 is -0.5..0.5, 0.5..1 is 0.5..-0.5
+            *sample = if self.period < 0.5 {
+                // Scale to -0.5..0.5 (0..1 - 0.5)
+                self.period * 2. - 0.5
+            } else {
+                // >= 0.5..1.0
+                // Scale to -0.5..0.5, invert
+                -((self.period - 0.5) * 2. - 0.5)
+            };
+        }
+        out.len()
+    }
+
+    fn retrigger(&mut self) -> bool {
+        self.period = 0.;
+        true
+    }
+}
 
 impl TriangleWave {
     pub fn new(frequency: f32) -> Self {
@@ -310,7 +718,7 @@ impl TriangleWave {
         }
     }
 
-    pub fn as_raw(self) -> RawSource {
+    pub fn into_raw(self) -> RawSource {
         RawSource::new(self)
     }
 }
@@ -325,39 +733,54 @@ impl Iterator for TriangleWave {
     type Item = f32;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let p_step = self.frequency / SAMPLE_RATE;
-
-        self.period += p_step;
-        if self.period > 1.0 {
-            self.period -= 1.0;
-        }
-
-        // low to high to low
-        // 0..0.5 is -0.5..0.5, 0.5..1 is 0.5..-0.5
-        if self.period < 0.5 {
-            // Scale to -0.5..0.5 (0..1 - 0.5)
-            let sample = self.period * 2. - 0.5;
-            Some(sample)
-        } else {
-            // >= 0.5..1.0
-            // Scale to -0.5..0.5, invert
-            let sample = (self.period - 0.5) * 2. - 0.5;
-            Some(sample * -1.)
-        }
+        next_sample(self)
     }
 }
 
 /// Noise from LFSR, based on the gameboy noise channel
 /// 15 bit LFSR, sets 15 as bit 1 ^ bit 0 on shift
+/// In short mode also sets bit 7, repeating every 127 shifts for metallic, tonal noise
 /// Scaled to -0.5 to 0.5
 pub struct NoiseLFSR {
     frequency: f32,
     period: f32,
     lfsr: u16,
     last: u16,
+    short: bool,
 }
 
-impl GenSource for NoiseLFSR {}
+impl GenSource for NoiseLFSR {
+    fn fill(&mut self, out: &mut [f32]) -> usize {
+        let p_step = self.frequency / sample_rate();
+        for sample in out.iter_mut() {
+            self.period += p_step;
+            if self.period > 1.0 {
+                self.period -= 1.0;
+                // Update LFSR state
+                let mut bit = self.lfsr & 0x01;
+                self.last = bit;
+
+                let mut lfsr = self.lfsr >> 1;
+                bit ^= lfsr & 0x01;
+                lfsr |= bit << 14;
+                if self.short {
+                    lfsr = (lfsr & !(1 << 6)) | (bit << 6);
+                }
+                self.lfsr = lfsr;
+            }
+
+            *sample = if self.last == 0 { -0.5 } else { 0.5 };
+        }
+        out.len()
+    }
+
+    fn retrigger(&mut self) -> bool {
+        self.period = 0.;
+        self.lfsr = 1;
+        self.last = 1;
+        true
+    }
+}
 
 impl NoiseLFSR {
     pub fn new(frequency: f32) -> Self {
@@ -366,10 +789,17 @@ impl NoiseLFSR {
             period: 0.,
             lfsr: 1,
             last: 1,
+            short: false,
         }
     }
 
-    pub fn as_raw(self) -> RawSource {
+    /// 7 bit "short" mode
+    pub fn short(mut self) -> Self {
+        self.short = true;
+        self
+    }
+
+    pub fn into_raw(self) -> RawSource {
         RawSource::new(self)
     }
 }
@@ -384,34 +814,131 @@ impl Iterator for NoiseLFSR {
     type Item = f32;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let p_step = self.frequency / SAMPLE_RATE;
+        next_sample(self)
+    }
+}
+
+/// Colour of `Noise`, by how its power falls off with frequency
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub enum NoiseColour {
+    /// Flat, equal power at every frequency
+    White,
+    /// -3db per octave, equal power per octave
+    Pink,
+    /// -6db per octave, a low rumble
+    Brown,
+}
 
-        self.period += p_step;
-        if self.period > 1.0 {
-            self.period -= 1.0;
-            // Update LFSR state
-            let mut bit = self.lfsr & 0x01;
-            self.last = bit;
+/// Seeds each `Noise` differently, so overlapping hits don't phase.
+/// Stepped by the golden ratio, the first instance gets the same seed as it always did.
+static NOISE_SEED: AtomicU32 = AtomicU32::new(0x9E37_79B9);
+
+/// Random noise, ignores its frequency.
+/// Scaled to roughly -0.5 to 0.5
+pub struct Noise {
+    colour: NoiseColour,
+    /// xorshift state
+    seed: u32,
+    /// Pink filter stages, or the brown integrator
+    state: [f32; 7],
+}
 
-            let mut lfsr = self.lfsr >> 1;
-            bit = bit ^ (lfsr & 0x01);
-            lfsr |= bit << 14;
-            self.lfsr = lfsr;
+impl GenSource for Noise {
+    fn fill(&mut self, out: &mut [f32]) -> usize {
+        for sample in out.iter_mut() {
+            let white = self.white();
+            let b = &mut self.state;
+            *sample = match self.colour {
+                NoiseColour::White => white,
+                // Paul Kellet's refined pink filter, from musicdsp.org
+                NoiseColour::Pink => {
+                    b[0] = 0.99886 * b[0] + white * 0.0555179;
+                    b[1] = 0.99332 * b[1] + white * 0.0750759;
+                    b[2] = 0.96900 * b[2] + white * 0.153852;
+                    b[3] = 0.86650 * b[3] + white * 0.3104856;
+                    b[4] = 0.55000 * b[4] + white * 0.5329522;
+                    b[5] = -0.7616 * b[5] - white * 0.0168980;
+                    let pink = b[0] + b[1] + b[2] + b[3] + b[4] + b[5] + b[6] + white * 0.5362;
+                    b[6] = white * 0.115926;
+                    // 0.11 in the original, for white noise of -1.0 to 1.0
+                    pink * 0.22
+                }
+                // Leaky integrator, so it doesn't wander off
+                NoiseColour::Brown => {
+                    b[0] = (b[0] * 0.998 + white * 0.04).clamp(-1., 1.);
+                    b[0]
+                }
+            };
         }
+        out.len()
+    }
 
-        if self.last == 0 {
-            Some(-0.5)
-        } else {
-            Some(0.5)
+    /// Carries on from its seed, so each hit is still different
+    fn retrigger(&mut self) -> bool {
+        self.state = [0.; 7];
+        true
+    }
+}
+
+impl Noise {
+    pub fn new(colour: NoiseColour) -> Self {
+        Noise {
+            colour,
+            // xorshift is stuck at 0
+            seed: NOISE_SEED.fetch_add(0x9E37_79B9, Ordering::Relaxed).max(1),
+            state: [0.; 7],
         }
     }
+
+    pub fn into_raw(self) -> RawSource {
+        RawSource::new(self)
+    }
+
+    /// -0.5 to 0.5
+    fn white(&mut self) -> f32 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 17;
+        self.seed ^= self.seed << 5;
+        self.seed as f32 / u32::MAX as f32 - 0.5
+    }
+}
+
+impl Oscillator for Noise {
+    fn set_frequency(&mut self, _frequency: f32) {}
+}
+
+impl Iterator for Noise {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        next_sample(self)
+    }
 }
 
 pub struct SuperSaw {
     sub_oscillators: Vec<SawWave>,
 }
 
-impl GenSource for SuperSaw {}
+impl GenSource for SuperSaw {
+    fn fill(&mut self, out: &mut [f32]) -> usize {
+        fill_blocks(out, |block| {
+            let mut sub_block = [0.; BLOCK_SIZE];
+            let sub_block = &mut sub_block[..block.len()];
+            block.fill(0.);
+            for sub in &mut self.sub_oscillators {
+                sub.fill(sub_block);
+                for (sample, sub_sample) in block.iter_mut().zip(sub_block.iter()) {
+                    *sample += sub_sample;
+                }
+            }
+            block.len()
+        })
+    }
+
+    fn retrigger(&mut self) -> bool {
+        self.sub_oscillators.iter_mut().all(GenSource::retrigger)
+    }
+}
 
 impl SuperSaw {
     pub fn new(frequency: f32) -> Self {
@@ -425,6 +952,16 @@ impl SuperSaw {
 
         super_saw
     }
+
+    /// Anti-aliased (PolyBLEP) version of this oscillator
+    pub fn band_limited(self) -> Self {
+        let sub_oscillators = self
+            .sub_oscillators
+            .into_iter()
+            .map(SawWave::band_limited)
+            .collect();
+        SuperSaw { sub_oscillators }
+    }
 }
 
 impl Oscillator for SuperSaw {
@@ -441,95 +978,898 @@ impl Iterator for SuperSaw {
     type Item = f32;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let mut res = 0.0;
-        for sub in &mut self.sub_oscillators {
-            res += sub.next().unwrap();
-        }
-        Some(res)
+        next_sample(self)
     }
 }
 
-///! From [LP and HP Filter](https://www.musicdsp.org/en/latest/Filters/38-lp-and-hp-filter.html)
-/// Frequency in Hz
-/// Resonance is sqrt(2) (1.4142) to 0.1 low to high
-pub struct Vcf<T: GenSource> {
-    source: T,
-    frequency: f32,
-    resonance: f32,
-
-    input: [f32; 2],
-    output: [f32; 2],
-    // Cached when frequency changes
-    c: f32,
+/// A sine operator of an `FmVoice`
+pub struct Operator {
+    /// Frequency as a multiple of the voice frequency
+    ratio: f32,
+    /// Peak phase offset in radians added to the operators it modulates,
+    /// or output level if it's a carrier
+    index: f32,
+    /// Amount of its own last output fed back into its phase
+    feedback: f32,
+    envelope: RawSource,
+    phase: f32,
+    step: f32,
+    last: f32,
+    finished: bool,
 }
 
-impl<T> GenSource for Vcf<T> where T: GenSource {}
-
-impl<T> Vcf<T>
-where
-    T: GenSource,
-{
-    pub fn new(source: T, frequency: f32, resonance: f32) -> Self {
-        let mut vcf = Self {
-            source,
-            frequency,
-            resonance,
-            input: [0., 0.],
-            output: [0., 0.],
-            c: 0.0,
-        };
-
-        vcf.set_frequency(frequency);
-        vcf
+impl Operator {
+    pub fn new<E>(ratio: f32, index: f32, envelope: E) -> Self
+    where
+        E: GenSource,
+    {
+        Operator {
+            ratio,
+            index,
+            feedback: 0.,
+            envelope: RawSource::new(envelope),
+            phase: 0.,
+            step: 0.,
+            last: 0.,
+            finished: false,
+        }
     }
 
-    pub fn as_raw(self) -> RawSource {
-        RawSource::new(self)
+    pub fn with_feedback(mut self, feedback: f32) -> Self {
+        self.feedback = feedback;
+        self
     }
-}
 
-impl<T> Oscillator for Vcf<T>
-where
-    T: GenSource,
-{
     fn set_frequency(&mut self, frequency: f32) {
-        self.frequency = frequency;
-        self.c = 1.0 / (std::f32::consts::PI * frequency / SAMPLE_RATE as f32).tan();
+        self.step = frequency * self.ratio / sample_rate();
     }
-}
 
-impl<T> Iterator for Vcf<T>
-where
-    T: GenSource,
-{
-    type Item = f32;
+    fn retrigger(&mut self) -> bool {
+        self.phase = 0.;
+        self.last = 0.;
+        self.finished = false;
+        self.envelope.retrigger()
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let Some(input) = self.source.next() else {
-            return None;
+    /// `modulation` is in radians, `level` is the envelope's, `None` once it's ended
+    fn process(&mut self, modulation: f32, level: Option<f32>) -> f32 {
+        if self.finished {
+            return 0.;
+        }
+        let Some(level) = level else {
+            self.finished = true;
+            self.last = 0.;
+            return 0.;
         };
+        let phase = self.phase * std::f32::consts::TAU + modulation + self.last * self.feedback;
+        self.phase = (self.phase + self.step) % 1.0;
+        self.last = phase.sin() * level * self.index;
+        self.last
+    }
+}
 
-        let r = self.resonance;
-        let c = self.c;
-        let c2 = self.c * self.c;
+/// How the operators of an `FmVoice` modulate each other, in the order they're given
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+pub enum FmAlgorithm {
+    /// Each operator modulates the next, the last is heard
+    #[default]
+    Stack,
+    /// Every operator modulates the last, which is heard
+    Branch,
+    /// First modulates second, third modulates fourth, second and fourth are heard
+    Pairs,
+    /// No modulation, every operator is heard
+    Additive,
+}
+
+impl FmAlgorithm {
+    /// Whether operator `from` modulates operator `to`, of `count`
+    fn modulates(&self, from: usize, to: usize, count: usize) -> bool {
+        match self {
+            FmAlgorithm::Stack => to == from + 1,
+            FmAlgorithm::Branch => from < to && to == count - 1,
+            FmAlgorithm::Pairs => to == from + 1 && to % 2 == 1,
+            FmAlgorithm::Additive => false,
+        }
+    }
+
+    fn is_carrier(&self, operator: usize, count: usize) -> bool {
+        (operator + 1..count).all(|to| !self.modulates(operator, to, count))
+    }
+}
+
+pub const MAX_OPERATORS: usize = 4;
+
+/// 2 to 4 operator FM (phase modulation) voice.
+/// Ends once every carrier's envelope has ended.
+pub struct FmVoice {
+    operators: Vec<Operator>,
+    algorithm: FmAlgorithm,
+    carriers: Vec<bool>,
+    outputs: [f32; MAX_OPERATORS],
+}
+
+impl GenSource for FmVoice {
+    fn fill(&mut self, out: &mut [f32]) -> usize {
+        fill_blocks(out, |block| self.fill_block(block))
+    }
+
+    fn retrigger(&mut self) -> bool {
+        self.outputs = [0.; MAX_OPERATORS];
+        self.operators.iter_mut().all(Operator::retrigger)
+    }
+}
+
+impl FmVoice {
+    /// Takes 2 to `MAX_OPERATORS` operators, any past that are dropped.
+    /// Song files are checked by `Instrument::validate`.
+    pub fn new(frequency: f32, algorithm: FmAlgorithm, mut operators: Vec<Operator>) -> Self {
+        operators.truncate(MAX_OPERATORS);
+        let count = operators.len();
+        let mut voice = FmVoice {
+            operators,
+            algorithm,
+            carriers: (0..count)
+                .map(|op| algorithm.is_carrier(op, count))
+                .collect(),
+            outputs: [0.; MAX_OPERATORS],
+        };
+        voice.set_frequency(frequency);
+        voice
+    }
+
+    pub fn into_raw(self) -> RawSource {
+        RawSource::new(self)
+    }
+
+    fn fill_block(&mut self, out: &mut [f32]) -> usize {
+        let count = self.operators.len();
+        let mut levels = [[0.; BLOCK_SIZE]; MAX_OPERATORS];
+        let mut level_lens = [0; MAX_OPERATORS];
+        for (idx, operator) in self.operators.iter_mut().enumerate() {
+            if !operator.finished {
+                level_lens[idx] = operator.envelope.fill(&mut levels[idx][..out.len()]);
+            }
+        }
+        // Scaled to -0.5 to 0.5, like the other oscillators
+        let carriers = self.carriers.iter().filter(|&&carrier| carrier).count();
+
+        for (sample_idx, sample) in out.iter_mut().enumerate() {
+            let mut mix = 0.;
+            let mut playing = false;
+            // Modulators always come before the operators they modulate
+            for idx in 0..count {
+                let modulation: f32 = (0..idx)
+                    .filter(|&from| self.algorithm.modulates(from, idx, count))
+                    .map(|from| self.outputs[from])
+                    .sum();
+                let level = (sample_idx < level_lens[idx]).then(|| levels[idx][sample_idx]);
+                self.outputs[idx] = self.operators[idx].process(modulation, level);
+                if self.carriers[idx] {
+                    mix += self.outputs[idx];
+                    playing |= !self.operators[idx].finished;
+                }
+            }
+
+            if !playing {
+                return sample_idx;
+            }
+            *sample = mix * 0.5 / carriers as f32;
+        }
+        out.len()
+    }
+}
+
+impl Oscillator for FmVoice {
+    fn set_frequency(&mut self, frequency: f32) {
+        for operator in &mut self.operators {
+            operator.set_frequency(frequency);
+        }
+    }
+}
+
+impl Iterator for FmVoice {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        next_sample(self)
+    }
+}
+
+/// Lowest frequency a `KarplusStrong` string can be tuned to
+const MIN_STRING_FREQUENCY: f32 = 20.0;
+
+/// Seeds each `KarplusStrong` differently, so no two plucks sound the same
+static STRING_SEED: AtomicU64 = AtomicU64::new(0);
+
+/// Plucked string (Karplus-Strong): a burst of noise circling a delay line
+/// one period long, lowpassed a little more each time round.
+/// Never ends on its own, silent until plucked or excited.
+pub struct KarplusStrong {
+    buffer: Vec<f32>,
+    position: usize,
+    /// Delay in samples, a period at the current frequency
+    delay: f32,
+    /// Seconds for a note to fall to -60db
+    decay: f32,
+    /// 0.0 (dull) to 1.0 (bright), how little is lowpassed each time round
+    brightness: f32,
+    /// Gain each time round, from the decay and frequency
+    loop_gain: f32,
+    last: f32,
+    /// Velocity it was plucked at when built, plucked again on retrigger
+    plucked: f32,
+    excitation: Option<RawSource>,
+    rng: StdRng,
+}
+
+impl GenSource for KarplusStrong {
+    fn fill(&mut self, out: &mut [f32]) -> usize {
+        fill_blocks(out, |block| {
+            // Silent once the excitation ends
+            let mut excitation = [0.; BLOCK_SIZE];
+            if let Some(source) = &mut self.excitation {
+                let len = source.fill(&mut excitation[..block.len()]);
+                excitation[len..].fill(0.);
+            }
+
+            let length = self.buffer.len();
+            let weight = 0.5 + 0.5 * self.brightness;
+            for (sample, excitation) in block.iter_mut().zip(excitation) {
+                // Linear interpolation between the two samples either side of the delay
+                let read = (self.position + length) as f32 - self.delay;
+                let index = read.floor() as usize;
+                let fraction = read.fract();
+                let a = self.buffer[index % length];
+                let b = self.buffer[(index + 1) % length];
+                let delayed = a + (b - a) * fraction;
+
+                let output = (delayed * weight + self.last * (1. - weight)) * self.loop_gain;
+                self.last = delayed;
+
+                self.buffer[self.position] = output + excitation;
+                self.position = (self.position + 1) % length;
+                *sample = output;
+            }
+            block.len()
+        })
+    }
+
+    /// Plucked with new noise, so no two plucks sound the same
+    fn retrigger(&mut self) -> bool {
+        self.buffer.fill(0.);
+        self.position = 0;
+        self.last = 0.;
+        self.pluck(self.plucked);
+        self.excitation.as_mut().is_none_or(GenSource::retrigger)
+    }
+}
+
+impl KarplusStrong {
+    pub fn new(frequency: f32, decay: f32, brightness: f32) -> Self {
+        let length = (sample_rate() / MIN_STRING_FREQUENCY) as usize + 2;
+        let mut string = KarplusStrong {
+            buffer: vec![0.; length],
+            position: 0,
+            delay: 0.,
+            decay: decay.max(0.001),
+            brightness: brightness.clamp(0., 1.),
+            loop_gain: 0.,
+            last: 0.,
+            plucked: 0.,
+            excitation: None,
+            rng: StdRng::seed_from_u64(STRING_SEED.fetch_add(1, Ordering::Relaxed)),
+        };
+        string.set_frequency(frequency);
+        string
+    }
+
+    /// Pluck at `velocity` as soon as it starts playing
+    pub fn plucked(mut self, velocity: f32) -> Self {
+        self.pluck(velocity);
+        self.plucked = velocity;
+        self
+    }
+
+    /// Fill the string with noise, restarting the note
+    pub fn pluck(&mut self, velocity: f32) {
+        let length = self.buffer.len();
+        for offset in 1..=(self.delay as usize + 1).min(length) {
+            self.buffer[(self.position + length - offset) % length] =
+                self.rng.gen_range(-0.5..0.5) * velocity;
+        }
+    }
+
+    /// Signal fed into the string as it plays, eg: enveloped noise to bow it
+    pub fn with_excitation<T>(mut self, excitation: T) -> Self
+    where
+        T: GenSource,
+    {
+        self.excitation = Some(RawSource::new(excitation));
+        self
+    }
+}
+
+impl Oscillator for KarplusStrong {
+    fn set_frequency(&mut self, frequency: f32) {
+        let frequency = frequency.max(MIN_STRING_FREQUENCY);
+        // The loop filter delays by half a sample, less the brighter it is
+        let filter_delay = 0.5 * (1. - self.brightness);
+        self.delay = (sample_rate() / frequency - filter_delay).max(1.);
+        self.loop_gain = 0.001_f32.powf(1. / (frequency * self.decay));
+    }
+}
+
+impl Iterator for KarplusStrong {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        next_sample(self)
+    }
+}
+
+/// A resonant mode of a `Modal` voice
+#[derive(Clone, Copy, Debug)]
+pub struct Mode {
+    /// Frequency as a multiple of the voice frequency
+    pub ratio: f32,
+    /// Seconds to fall to -60db
+    pub decay: f32,
+    pub amplitude: f32,
+}
+
+/// Wooden bar, eg: a marimba
+pub const BAR_MODES: [Mode; 3] = [
+    Mode {
+        ratio: 1.0,
+        decay: 0.6,
+        amplitude: 1.0,
+    },
+    Mode {
+        ratio: 3.93,
+        decay: 0.15,
+        amplitude: 0.4,
+    },
+    Mode {
+        ratio: 9.54,
+        decay: 0.05,
+        amplitude: 0.15,
+    },
+];
+
+/// Struck glass or a small bell
+pub const GLASS_MODES: [Mode; 4] = [
+    Mode {
+        ratio: 1.0,
+        decay: 1.5,
+        amplitude: 1.0,
+    },
+    Mode {
+        ratio: 2.32,
+        decay: 1.0,
+        amplitude: 0.5,
+    },
+    Mode {
+        ratio: 4.25,
+        decay: 0.6,
+        amplitude: 0.3,
+    },
+    Mode {
+        ratio: 6.63,
+        decay: 0.3,
+        amplitude: 0.2,
+    },
+];
+
+/// Two pole resonator ringing at one mode
+struct Resonator {
+    mode: Mode,
+    /// Feedback coefficients
+    a1: f32,
+    a2: f32,
+    /// Input gain, so a strike of 1.0 peaks at `amplitude`
+    gain: f32,
+    y1: f32,
+    y2: f32,
+}
+
+impl Resonator {
+    fn new(mode: Mode) -> Self {
+        Resonator {
+            mode,
+            a1: 0.,
+            a2: 0.,
+            gain: 0.,
+            y1: 0.,
+            y2: 0.,
+        }
+    }
+
+    fn set_frequency(&mut self, frequency: f32) {
+        // Modes above nyquist are silenced, rather than aliasing
+        let omega = (std::f32::consts::TAU * frequency * self.mode.ratio / sample_rate())
+            .min(std::f32::consts::PI);
+        let r = 0.001_f32.powf(1. / (self.mode.decay.max(0.001) * sample_rate()));
+        self.a1 = 2. * r * omega.cos();
+        self.a2 = -r * r;
+        self.gain = self.mode.amplitude * omega.sin();
+    }
+
+    fn retrigger(&mut self) {
+        self.y1 = 0.;
+        self.y2 = 0.;
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let y = input * self.gain + self.a1 * self.y1 + self.a2 * self.y2;
+        self.y2 = self.y1;
+        self.y1 = y;
+        y
+    }
+}
+
+/// Modal synthesis: a bank of resonators, each ringing at one mode of a struck object.
+/// Never ends on its own, silent until struck or excited.
+pub struct Modal {
+    resonators: Vec<Resonator>,
+    /// Impulse to add on the next sample
+    strike: f32,
+    /// Velocity it was struck at when built, struck again on retrigger
+    struck: f32,
+    excitation: Option<RawSource>,
+}
+
+impl GenSource for Modal {
+    fn fill(&mut self, out: &mut [f32]) -> usize {
+        fill_blocks(out, |block| {
+            // Silent once the excitation ends
+            let mut excitation = [0.; BLOCK_SIZE];
+            if let Some(source) = &mut self.excitation {
+                let len = source.fill(&mut excitation[..block.len()]);
+                excitation[len..].fill(0.);
+            }
+
+            for (sample, excitation) in block.iter_mut().zip(excitation) {
+                let input = excitation + std::mem::take(&mut self.strike);
+                let mut output = 0.;
+                for resonator in &mut self.resonators {
+                    output += resonator.process(input);
+                }
+                // Scaled to -0.5 to 0.5, like the other oscillators
+                *sample = output * 0.5;
+            }
+            block.len()
+        })
+    }
+
+    fn retrigger(&mut self) -> bool {
+        self.resonators.iter_mut().for_each(Resonator::retrigger);
+        self.strike = self.struck;
+        self.excitation.as_mut().is_none_or(GenSource::retrigger)
+    }
+}
+
+impl Modal {
+    pub fn new(frequency: f32, modes: &[Mode]) -> Self {
+        let mut modal = Modal {
+            resonators: modes.iter().copied().map(Resonator::new).collect(),
+            strike: 0.,
+            struck: 0.,
+            excitation: None,
+        };
+        modal.set_frequency(frequency);
+        modal
+    }
+
+    /// Strike at `velocity` as soon as it starts playing
+    pub fn struck(mut self, velocity: f32) -> Self {
+        self.strike(velocity);
+        self.struck += velocity;
+        self
+    }
+
+    /// Excite every mode with an impulse
+    pub fn strike(&mut self, velocity: f32) {
+        self.strike += velocity;
+    }
+
+    /// Signal fed into the resonators as it plays, eg: a noise burst for a softer mallet
+    pub fn with_excitation<T>(mut self, excitation: T) -> Self
+    where
+        T: GenSource,
+    {
+        self.excitation = Some(RawSource::new(excitation));
+        self
+    }
+}
+
+impl Oscillator for Modal {
+    fn set_frequency(&mut self, frequency: f32) {
+        for resonator in &mut self.resonators {
+            resonator.set_frequency(frequency);
+        }
+    }
+}
+
+impl Iterator for Modal {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        next_sample(self)
+    }
+}
+
+///! From [LP and HP Filter](https://www.musicdsp.org/en/latest/Filters/38-lp-and-hp-filter.html)
+/// Frequency in Hz
+/// Resonance is sqrt(2) (1.4142) to 0.1 low to high
+pub struct Vcf<T: GenSource> {
+    source: T,
+    frequency: f32,
+    resonance: f32,
+
+    input: [f32; 2],
+    output: [f32; 2],
+    // Cached when frequency or resonance changes
+    a: [f32; 3],
+    b: [f32; 2],
+}
+
+impl<T> GenSource for Vcf<T>
+where
+    T: GenSource,
+{
+    fn fill(&mut self, out: &mut [f32]) -> usize {
+        let len = self.source.fill(out);
+
+        let [a1, a2, a3] = self.a;
+        let [b1, b2] = self.b;
+        for sample in &mut out[..len] {
+            let input = *sample;
+            let output = (a1 * input) + (a2 * self.input[0]) + (a3 * self.input[1])
+                - (b1 * self.output[0])
+                - (b2 * self.output[1]);
+
+            self.input[1] = self.input[0];
+            self.input[0] = input;
+
+            self.output[1] = self.output[0];
+            self.output[0] = output;
+
+            *sample = output;
+        }
+        len
+    }
+
+    fn retrigger(&mut self) -> bool {
+        self.input = [0., 0.];
+        self.output = [0., 0.];
+        self.source.retrigger()
+    }
+}
+
+impl<T> Vcf<T>
+where
+    T: GenSource,
+{
+    pub fn new(source: T, frequency: f32, resonance: f32) -> Self {
+        let mut vcf = Self {
+            source,
+            frequency,
+            resonance,
+            input: [0., 0.],
+            output: [0., 0.],
+            a: [0.; 3],
+            b: [0.; 2],
+        };
+
+        vcf.set_frequency(frequency);
+        vcf
+    }
+
+    pub fn into_raw(self) -> RawSource {
+        RawSource::new(self)
+    }
+
+    fn update_coefficients(&mut self) {
+        let r = self.resonance;
+        let c = 1.0 / (std::f32::consts::PI * self.frequency / sample_rate()).tan();
+        let c2 = c * c;
 
         let a1 = 1.0 / (1.0 + (r * c) + c2);
-        let a2 = 2.0 * a1;
-        let a3 = a1;
-        let b1 = 2.0 * (1.0 - c2) * a1;
-        let b2 = (1.0 - (r * c) + c2) * a1;
+        self.a = [a1, 2.0 * a1, a1];
+        self.b = [2.0 * (1.0 - c2) * a1, (1.0 - (r * c) + c2) * a1];
+    }
+}
+
+impl<T> Oscillator for Vcf<T>
+where
+    T: GenSource,
+{
+    fn set_frequency(&mut self, frequency: f32) {
+        self.frequency = frequency;
+        self.update_coefficients();
+    }
+}
+
+impl<T> Iterator for Vcf<T>
+where
+    T: GenSource,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        next_sample(self)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+pub enum FilterMode {
+    #[default]
+    Lowpass,
+    Highpass,
+    Bandpass,
+    Notch,
+}
+
+/// From [Cytomic SVF](https://cytomic.com/files/dsp/SvfLinearTrapOptimised2.pdf)
+/// State variable filter, stable while the cutoff is swept.
+/// Resonance is 0.0 to 1.0, self oscillating near 1.0.
+/// Cutoff CV is in volts (see `frequency_per_volt`), resonance CV is added to the resonance.
+pub struct Svf<T: GenSource> {
+    source: T,
+    mode: FilterMode,
+    /// Cutoff in volts
+    base_voltage: f32,
+    resonance: f32,
+    cutoff_cv: Option<RawSource>,
+    resonance_cv: Option<RawSource>,
+    last_cv: [f32; 2],
+
+    // Cached when cutoff or resonance changes
+    k: f32,
+    a: [f32; 3],
+    ic1eq: f32,
+    ic2eq: f32,
+}
+
+impl<T> GenSource for Svf<T>
+where
+    T: GenSource,
+{
+    fn fill(&mut self, out: &mut [f32]) -> usize {
+        fill_blocks(out, |block| self.fill_block(block))
+    }
+
+    fn retrigger(&mut self) -> bool {
+        self.ic1eq = 0.;
+        self.ic2eq = 0.;
+        self.last_cv = [0.; 2];
+        self.update_coefficients();
+        self.source.retrigger()
+            && self.cutoff_cv.as_mut().is_none_or(GenSource::retrigger)
+            && self.resonance_cv.as_mut().is_none_or(GenSource::retrigger)
+    }
+}
+
+impl<T> Svf<T>
+where
+    T: GenSource,
+{
+    pub fn new(source: T, mode: FilterMode, frequency: f32, resonance: f32) -> Self {
+        let mut svf = Svf {
+            source,
+            mode,
+            base_voltage: volts_per_frequency(frequency),
+            resonance,
+            cutoff_cv: None,
+            resonance_cv: None,
+            last_cv: [0.; 2],
+            k: 0.,
+            a: [0.; 3],
+            ic1eq: 0.,
+            ic2eq: 0.,
+        };
+        svf.update_coefficients();
+        svf
+    }
+
+    pub fn with_cutoff_cv<C>(mut self, cv: C) -> Self
+    where
+        C: GenSource,
+    {
+        self.cutoff_cv = Some(RawSource::new(cv));
+        self
+    }
+
+    pub fn with_resonance_cv<C>(mut self, cv: C) -> Self
+    where
+        C: GenSource,
+    {
+        self.resonance_cv = Some(RawSource::new(cv));
+        self
+    }
+
+    pub fn into_raw(self) -> RawSource {
+        RawSource::new(self)
+    }
+
+    pub fn set_resonance(&mut self, resonance: f32) {
+        if resonance != self.resonance {
+            self.resonance = resonance;
+            self.update_coefficients();
+        }
+    }
+
+    fn fill_block(&mut self, out: &mut [f32]) -> usize {
+        let len = self.source.fill(out);
+
+        // CV keeps its last value once it ends
+        let mut cutoff_cv = [0.; BLOCK_SIZE];
+        let cutoff_len = match &mut self.cutoff_cv {
+            Some(cv) => cv.fill(&mut cutoff_cv[..len]),
+            None => 0,
+        };
+        let mut resonance_cv = [0.; BLOCK_SIZE];
+        let resonance_len = match &mut self.resonance_cv {
+            Some(cv) => cv.fill(&mut resonance_cv[..len]),
+            None => 0,
+        };
+
+        for (idx, sample) in out[..len].iter_mut().enumerate() {
+            let mut cv = self.last_cv;
+            if idx < cutoff_len {
+                cv[0] = cutoff_cv[idx];
+            }
+            if idx < resonance_len {
+                cv[1] = resonance_cv[idx];
+            }
+            if cv != self.last_cv {
+                self.last_cv = cv;
+                self.update_coefficients();
+            }
+
+            let input = *sample;
+            let [a1, a2, a3] = self.a;
+            let v3 = input - self.ic2eq;
+            let v1 = a1 * self.ic1eq + a2 * v3;
+            let v2 = self.ic2eq + a2 * self.ic1eq + a3 * v3;
+            self.ic1eq = 2.0 * v1 - self.ic1eq;
+            self.ic2eq = 2.0 * v2 - self.ic2eq;
+
+            let low = v2;
+            let high = input - self.k * v1 - v2;
+            *sample = match self.mode {
+                FilterMode::Lowpass => low,
+                FilterMode::Highpass => high,
+                FilterMode::Bandpass => v1,
+                FilterMode::Notch => low + high,
+            };
+        }
+        len
+    }
+
+    fn update_coefficients(&mut self) {
+        let frequency = frequency_per_volt(self.base_voltage + self.last_cv[0])
+            .clamp(10.0, sample_rate() * 0.49);
+        let resonance = (self.resonance + self.last_cv[1]).clamp(0.0, 1.0);
+
+        let g = (std::f32::consts::PI * frequency / sample_rate()).tan();
+        self.k = 2.0 - 2.0 * resonance;
+        let a1 = 1.0 / (1.0 + g * (g + self.k));
+        let a2 = g * a1;
+        self.a = [a1, a2, g * a2];
+    }
+}
+
+impl<T> Oscillator for Svf<T>
+where
+    T: GenSource,
+{
+    fn set_frequency(&mut self, frequency: f32) {
+        self.base_voltage = volts_per_frequency(frequency);
+        self.update_coefficients();
+    }
+}
+
+impl<T> Iterator for Svf<T>
+where
+    T: GenSource,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        next_sample(self)
+    }
+}
+
+/// Left and right gain of a constant power pan, -1.0 (left) to 1.0 (right)
+pub fn pan_gains(pan: f32) -> (f32, f32) {
+    let angle = (pan.clamp(-1.0, 1.0) + 1.0) * std::f32::consts::FRAC_PI_4;
+    (angle.cos(), angle.sin())
+}
+
+/// Places a mono source in stereo, -1.0 (left) to 1.0 (right)
+pub struct Pan<T: GenSource> {
+    source: T,
+    left: f32,
+    right: f32,
+    /// Right sample of the current frame
+    pending: Option<f32>,
+}
+
+impl<T> GenSource for Pan<T>
+where
+    T: GenSource,
+{
+    fn fill(&mut self, mut out: &mut [f32]) -> usize {
+        let mut rendered = 0;
+        if let Some(right) = self.pending.take() {
+            let Some((first, rest)) = out.split_first_mut() else {
+                self.pending = Some(right);
+                return 0;
+            };
+            *first = right;
+            out = rest;
+            rendered = 1;
+        }
+
+        rendered
+            + fill_blocks(out, |block| {
+                // Mono samples are rendered into the first half, then spread out from the end
+                let frames = block.len().div_ceil(2);
+                let len = self.source.fill(&mut block[..frames]);
+                let mut mono = [0.; BLOCK_SIZE];
+                mono[..len].copy_from_slice(&block[..len]);
+
+                let mut rendered = 0;
+                for sample in &mono[..len] {
+                    block[rendered] = sample * self.left;
+                    rendered += 1;
+                    if rendered == block.len() {
+                        // Odd length, the right sample starts the next block
+                        self.pending = Some(sample * self.right);
+                        break;
+                    }
+                    block[rendered] = sample * self.right;
+                    rendered += 1;
+                }
+                rendered
+            })
+    }
 
-        let output = (a1 * input) + (a2 * self.input[0]) + (a3 * self.input[1])
-            - (b1 * self.output[0])
-            - (b2 * self.output[1]);
+    fn retrigger(&mut self) -> bool {
+        self.pending = None;
+        self.source.retrigger()
+    }
+}
 
-        self.input[1] = self.input[0];
-        self.input[0] = input;
+impl<T> Pan<T>
+where
+    T: GenSource,
+{
+    pub fn new(source: T, pan: f32) -> Self {
+        let (left, right) = pan_gains(pan);
+        Pan {
+            source,
+            left,
+            right,
+            pending: None,
+        }
+    }
 
-        self.output[1] = self.output[0];
-        self.output[0] = output;
+    pub fn into_raw(self) -> RawSource {
+        RawSource::stereo(self)
+    }
+}
 
-        Some(output)
+impl<T> Iterator for Pan<T>
+where
+    T: GenSource,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        next_sample(self)
     }
 }
 
@@ -543,6 +1883,21 @@ where
     S: GenSource,
     E: GenSource,
 {
+    fn fill(&mut self, out: &mut [f32]) -> usize {
+        fill_blocks(out, |block| {
+            let len = self.source.fill(block);
+            let mut envelope = [0.; BLOCK_SIZE];
+            let len = self.envelope.fill(&mut envelope[..len]);
+            for (sample, env) in block[..len].iter_mut().zip(envelope) {
+                *sample = if env > 0. { *sample * env } else { 0.0 };
+            }
+            len
+        })
+    }
+
+    fn retrigger(&mut self) -> bool {
+        self.source.retrigger() && self.envelope.retrigger()
+    }
 }
 
 impl<S, E> Vca<S, E>
@@ -554,7 +1909,7 @@ where
         Vca { source, envelope }
     }
 
-    pub fn as_raw(self) -> RawSource {
+    pub fn into_raw(self) -> RawSource {
         RawSource::new(self)
     }
 }
@@ -567,15 +1922,7 @@ where
     type Item = f32;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if let (Some(sample), Some(env)) = (self.source.next(), self.envelope.next()) {
-            if env > 0. {
-                Some(sample * env)
-            } else {
-                Some(0.0)
-            }
-        } else {
-            None
-        }
+        next_sample(self)
     }
 }
 
@@ -592,7 +1939,32 @@ pub struct Envelope {
     time: f32,
 }
 
-impl GenSource for Envelope {}
+impl GenSource for Envelope {
+    fn fill(&mut self, out: &mut [f32]) -> usize {
+        let step = 1. / sample_rate();
+        for (idx, sample) in out.iter_mut().enumerate() {
+            let time = self.time;
+            self.time += step;
+
+            *sample = if self.time < self.attack {
+                self.amplitude * (time / self.attack)
+            } else if self.time < self.attack + self.hold {
+                self.amplitude
+            } else if self.time < self.attack + self.hold + self.release {
+                self.amplitude * (1.0 - ((time - (self.attack + self.hold)) / self.release))
+            } else {
+                self.time = time;
+                return idx;
+            };
+        }
+        out.len()
+    }
+
+    fn retrigger(&mut self) -> bool {
+        self.time = 0.;
+        true
+    }
+}
 
 impl Envelope {
     pub fn new(amplitude: f32, attack: f32, hold: f32, release: f32) -> Self {
@@ -605,7 +1977,7 @@ impl Envelope {
         }
     }
 
-    pub fn as_raw(self) -> RawSource {
+    pub fn into_raw(self) -> RawSource {
         RawSource::new(self)
     }
 }
@@ -614,31 +1986,260 @@ impl Iterator for Envelope {
     type Item = f32;
 
     fn next(&mut self) -> Option<Self::Item> {
-        const STEP: f32 = 1. / 44100.;
-        let time = self.time;
-        self.time += STEP;
-
-        if self.time < self.attack {
-            let res = self.amplitude * (time / self.attack);
-            Some(res)
-        } else if self.time < self.attack + self.hold {
-            Some(self.amplitude)
-        } else if self.time < self.attack + self.hold + self.release {
-            let res = self.amplitude * (1.0 - ((time - (self.attack + self.hold)) / self.release));
-            Some(res)
+        next_sample(self)
+    }
+}
+
+/// Gate signal, 1.0 while the note is held and 0.0 once released.
+/// Never ends on its own; drives an `Adsr`.
+pub struct Gate {
+    open: Arc<AtomicBool>,
+    /// Samples left before releasing, for gates of a known length
+    remaining: Option<usize>,
+    /// Samples the gate was held for when built, `None` if held until released by a handle
+    length: Option<usize>,
+}
+
+/// Releases a `Gate` from outside the audio thread (eg, on key up).
+#[derive(Clone)]
+pub struct GateHandle(Arc<AtomicBool>);
+
+impl GateHandle {
+    pub fn release(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
+
+impl GenSource for Gate {
+    fn fill(&mut self, out: &mut [f32]) -> usize {
+        // Released from outside at most once a block
+        let mut open = self.open.load(Ordering::Relaxed);
+        for sample in out.iter_mut() {
+            if let Some(remaining) = &mut self.remaining {
+                if *remaining == 0 {
+                    self.open.store(false, Ordering::Relaxed);
+                    open = false;
+                } else {
+                    *remaining -= 1;
+                }
+            }
+
+            *sample = if open { 1.0 } else { 0.0 };
+        }
+        out.len()
+    }
+
+    /// Only timed gates, a `GateHandle` can't know its gate opened again
+    fn retrigger(&mut self) -> bool {
+        if self.length.is_none() {
+            return false;
+        }
+        self.open.store(true, Ordering::Relaxed);
+        self.remaining = self.length;
+        true
+    }
+}
+
+impl Gate {
+    /// Gate held until `GateHandle::release` is called
+    pub fn new() -> (Self, GateHandle) {
+        let open = Arc::new(AtomicBool::new(true));
+        let gate = Gate {
+            open: open.clone(),
+            remaining: None,
+            length: None,
+        };
+        (gate, GateHandle(open))
+    }
+
+    /// Gate held for `seconds`, then released
+    pub fn timed(seconds: f32) -> Self {
+        let length = Some((seconds * sample_rate()) as usize);
+        Gate {
+            open: Arc::new(AtomicBool::new(true)),
+            remaining: length,
+            length,
+        }
+    }
+}
+
+impl Iterator for Gate {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        next_sample(self)
+    }
+}
+
+enum AdsrStage {
+    Idle,
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+/// Attack/decay/sustain/release envelope, driven by a gate.
+/// A gate above 0.5 (re)starts the attack, dropping below starts the release.
+/// Ends once the release finishes.
+pub struct Adsr<G: GenSource> {
+    /// Maximum amplitude of the envelope, from 0.0 to 1.0
+    amplitude: f32,
+    /// Time in seconds of attack
+    attack: f32,
+    /// Time in seconds of decay to the sustain level
+    decay: f32,
+    /// Level held while the gate is open, from 0.0 to 1.0 of `amplitude`
+    sustain: f32,
+    /// Time in seconds of release
+    release: f32,
+    gate: G,
+
+    stage: AdsrStage,
+    level: f32,
+    /// Level the release started from
+    release_level: f32,
+}
+
+impl<G> GenSource for Adsr<G>
+where
+    G: GenSource,
+{
+    fn fill(&mut self, out: &mut [f32]) -> usize {
+        fill_blocks(out, |block| self.fill_block(block))
+    }
+
+    fn retrigger(&mut self) -> bool {
+        self.stage = AdsrStage::Idle;
+        self.level = 0.;
+        self.release_level = 0.;
+        self.gate.retrigger()
+    }
+}
+
+impl<G> Adsr<G>
+where
+    G: GenSource,
+{
+    pub fn new(
+        amplitude: f32,
+        attack: f32,
+        decay: f32,
+        sustain: f32,
+        release: f32,
+        gate: G,
+    ) -> Self {
+        Adsr {
+            amplitude: amplitude.clamp(0.0, 1.0),
+            attack,
+            decay,
+            sustain: sustain.clamp(0.0, 1.0),
+            release,
+            gate,
+            stage: AdsrStage::Idle,
+            level: 0.,
+            release_level: 0.,
+        }
+    }
+
+    pub fn into_raw(self) -> RawSource {
+        RawSource::new(self)
+    }
+
+    fn fill_block(&mut self, out: &mut [f32]) -> usize {
+        let mut gate = [0.; BLOCK_SIZE];
+        let gate_len = self.gate.fill(&mut gate[..out.len()]);
+
+        for (idx, sample) in out.iter_mut().enumerate() {
+            let gate = idx < gate_len && gate[idx] > 0.5;
+
+            match self.stage {
+                AdsrStage::Idle | AdsrStage::Release if gate => self.stage = AdsrStage::Attack,
+                AdsrStage::Idle => return idx,
+                AdsrStage::Attack | AdsrStage::Decay | AdsrStage::Sustain if !gate => {
+                    self.stage = AdsrStage::Release;
+                    self.release_level = self.level;
+                }
+                _ => {}
+            }
+
+            let sustain_level = self.amplitude * self.sustain;
+            match self.stage {
+                AdsrStage::Attack => {
+                    self.level += Self::step(self.amplitude, self.attack);
+                    if self.level >= self.amplitude {
+                        self.level = self.amplitude;
+                        self.stage = AdsrStage::Decay;
+                    }
+                }
+                AdsrStage::Decay => {
+                    self.level -= Self::step(self.amplitude - sustain_level, self.decay);
+                    if self.level <= sustain_level {
+                        self.level = sustain_level;
+                        self.stage = AdsrStage::Sustain;
+                    }
+                }
+                AdsrStage::Sustain => {
+                    self.level = sustain_level;
+                }
+                AdsrStage::Release => {
+                    self.level -= Self::step(self.release_level, self.release);
+                    if self.level <= 0. {
+                        self.level = 0.;
+                        self.stage = AdsrStage::Idle;
+                        return idx;
+                    }
+                }
+                AdsrStage::Idle => {}
+            }
+
+            *sample = self.level;
+        }
+        out.len()
+    }
+
+    /// Change in level per sample to cover `range` over `time` seconds
+    fn step(range: f32, time: f32) -> f32 {
+        if time > 0. {
+            range / (time * sample_rate())
         } else {
-            self.time = time;
-            None
+            range
         }
     }
 }
 
+impl<G> Iterator for Adsr<G>
+where
+    G: GenSource,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        next_sample(self)
+    }
+}
+
 pub struct Attenuator<T: GenSource> {
     source: T,
     attenuation: f32,
 }
 
-impl<T> GenSource for Attenuator<T> where T: GenSource {}
+impl<T> GenSource for Attenuator<T>
+where
+    T: GenSource,
+{
+    fn fill(&mut self, out: &mut [f32]) -> usize {
+        let len = self.source.fill(out);
+        for sample in &mut out[..len] {
+            *sample *= self.attenuation;
+        }
+        len
+    }
+
+    fn retrigger(&mut self) -> bool {
+        self.source.retrigger()
+    }
+}
 
 impl<T> Attenuator<T>
 where
@@ -651,7 +2252,7 @@ where
         }
     }
 
-    pub fn as_raw(self) -> RawSource {
+    pub fn into_raw(self) -> RawSource {
         RawSource::new(self)
     }
 }
@@ -663,10 +2264,6 @@ where
     type Item = f32;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if let Some(sample) = self.source.next() {
-            Some(sample * self.attenuation)
-        } else {
-            None
-        }
+        next_sample(self)
     }
 }
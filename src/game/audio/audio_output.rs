@@ -1,58 +1,192 @@
+use super::audio_generator::sample_rate;
+use super::backend::{AudioBackend, NullBackend};
+use super::mixer::{Mixer, MixerSource};
 use super::Audio;
 use bevy::prelude::*;
-use bevy::reflect::TypeUuid;
-use rodio::{OutputStream, OutputStreamHandle, Sink};
-
-/// Copy-pasta from bevy_audio.
-///
-/// ## Note
-///
-/// This will leak [`rodio::OutputStream`](rodio::OutputStream)
-/// using [`std::mem::forget`].
-/// This is to prevent it being dropped and stopping audio.
-///
-/// When used once, this is fine. Multiple times will leak memory.
+use bevy::window::WindowFocused;
+use rodio::source::Source;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Frames a `MixStream` pulls from the mix per lock.
+const STREAM_BLOCK_FRAMES: usize = 512;
+/// Seconds between attempts to reopen a lost backend.
+const RETRY_SECONDS: f32 = 2.0;
+
+/// Everything is played through `mixer`, out to the `AudioDevice`.
 #[derive(Resource)]
 pub struct AudioOutput {
-    pub(crate) stream_handle: Option<OutputStreamHandle>,
+    pub mixer: Mixer,
+    /// Outlives the backend's stream, so the mix carries on when it's reopened
+    mix: Arc<Mutex<MixerSource>>,
+    paused: Arc<AtomicBool>,
 }
 
 impl Default for AudioOutput {
     fn default() -> Self {
-        if let Ok((stream, stream_handle)) = OutputStream::try_default() {
-            // Leak `OutputStream` to prevent audio from stopping.
-            std::mem::forget(stream);
-            Self {
-                stream_handle: Some(stream_handle),
-            }
-        } else {
-            warn!("No audio device found.");
-            Self {
-                stream_handle: None,
-            }
+        let (mixer, mixer_source) = Mixer::new();
+        Self {
+            mixer,
+            mix: Arc::new(Mutex::new(mixer_source)),
+            paused: Arc::default(),
         }
     }
 }
 
 impl AudioOutput {
-    fn play_audio(&self, audio: &mut Audio) {
-        let Some(stream_handle) = &self.stream_handle else {
-            return;
-        };
+    /// Stop the mix where it is, songs and sounds carry on from there on resume
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Relaxed);
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
 
+    /// Silence the output, everything keeps playing underneath
+    pub fn set_muted(&self, muted: bool) {
+        self.mixer.set_muted(muted);
+    }
+
+    pub fn muted(&self) -> bool {
+        self.mixer.muted()
+    }
+
+    fn play_audio(&self, audio: &mut Audio) {
         let mut queue = audio.queue.write().unwrap();
-        while let Some(source) = queue.pop_front() {
-            stream_handle.play_raw(source).unwrap();
+        while let Some((source, bus)) = queue.pop_front() {
+            self.mixer.play(source, bus);
+        }
+    }
+
+    /// A stream of the mix for a backend to play.
+    /// Only one should be pulled from at a time, they all advance the same mix.
+    pub fn stream(&self) -> MixStream {
+        MixStream {
+            mix: self.mix.clone(),
+            paused: self.paused.clone(),
+            buffer: Vec::with_capacity(STREAM_BLOCK_FRAMES * 2),
+            position: 0,
         }
     }
 }
 
-#[derive(Resource, TypeUuid)]
-#[uuid = "D6913CD1-1B92-46FB-8298-1974DB6A7CC4"]
-pub struct AudioSink {
-    sink: Sink,
+/// The interleaved stereo mix, silent while paused.
+/// Never ends.
+pub struct MixStream {
+    mix: Arc<Mutex<MixerSource>>,
+    paused: Arc<AtomicBool>,
+    /// Interleaved left/right samples
+    buffer: Vec<f32>,
+    position: usize,
+}
+
+impl MixStream {
+    fn fill(&mut self) {
+        self.buffer.clear();
+        self.position = 0;
+        if self.paused.load(Ordering::Relaxed) {
+            self.buffer.resize(STREAM_BLOCK_FRAMES * 2, 0.0);
+        } else {
+            let mut mix = self.mix.lock().unwrap();
+            self.buffer
+                .extend(mix.by_ref().take(STREAM_BLOCK_FRAMES * 2));
+        }
+    }
+}
+
+impl Source for MixStream {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        2
+    }
+
+    fn sample_rate(&self) -> u32 {
+        sample_rate() as u32
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+impl Iterator for MixStream {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.position >= self.buffer.len() {
+            self.fill();
+        }
+        let sample = self.buffer[self.position];
+        self.position += 1;
+        Some(sample)
+    }
+}
+
+/// The `AudioBackend` playing `AudioOutput`.
+/// Backends needn't be `Send` (eg: rodio's stream isn't), so it's a non-send resource.
+/// Reopened by `audio_device_system` if the backend closes.
+pub struct AudioDevice {
+    backend: Box<dyn AudioBackend>,
+    /// Keeps the mix going while `backend` is closed
+    fallback: NullBackend,
+    retry: Timer,
+}
+
+impl AudioDevice {
+    pub fn open(mut backend: Box<dyn AudioBackend>, output: &AudioOutput) -> Self {
+        if let Err(err) = backend.open(output.stream()) {
+            warn!("Couldn't open the audio output: {}", err);
+        }
+        let mut fallback = NullBackend::default();
+        // Never fails
+        let _ = fallback.open(output.stream());
+        AudioDevice {
+            backend,
+            fallback,
+            retry: Timer::from_seconds(RETRY_SECONDS, TimerMode::Repeating),
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.backend.is_open()
+    }
+}
+
+/// Update the backend, and keep trying to reopen it if it's closed (eg: the device was unplugged).
+pub fn audio_device_system(
+    mut device: NonSendMut<AudioDevice>,
+    audio_output: Res<AudioOutput>,
+    time: Res<Time>,
+) {
+    let delta = time.delta_seconds();
+    device.backend.update(delta);
+    if device.backend.is_open() {
+        return;
+    }
+
+    device.fallback.update(delta);
+    if device.retry.tick(time.delta()).just_finished()
+        && device.backend.open(audio_output.stream()).is_ok()
+    {
+        info!("Audio output reopened.");
+    }
 }
 
 pub fn play_queued_audio_system(audio_output: Res<AudioOutput>, mut audio: ResMut<Audio>) {
     audio_output.play_audio(&mut audio);
 }
+
+/// Pause the mix while the window is in the background
+pub fn pause_unfocused_system(
+    mut focus_events: EventReader<WindowFocused>,
+    audio_output: Res<AudioOutput>,
+) {
+    for event in focus_events.iter() {
+        audio_output.set_paused(!event.focused);
+    }
+}
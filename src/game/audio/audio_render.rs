@@ -0,0 +1,460 @@
+use super::audio_generator::*;
+use super::sequencer::Sequencer;
+use crate::game::instrument::{Instrument, Instruments};
+use crate::game::song::{self, Note, Song};
+use crate::game::song_file;
+use rodio::source::Source;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Longest a single source (or the tail of a song) is rendered for, in seconds.
+/// Raw oscillators never end on their own, so this bounds them.
+pub const MAX_RENDER_SECONDS: f32 = 10.0;
+
+/// Sample format of a rendered WAV file
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WavFormat {
+    /// 16 bit signed PCM, clipped to -1.0..1.0
+    Int16,
+    /// 32 bit IEEE float, unclipped
+    Float32,
+}
+
+impl WavFormat {
+    fn format_tag(&self) -> u16 {
+        match self {
+            WavFormat::Int16 => 1,
+            WavFormat::Float32 => 3,
+        }
+    }
+
+    fn bits_per_sample(&self) -> u16 {
+        match self {
+            WavFormat::Int16 => 16,
+            WavFormat::Float32 => 32,
+        }
+    }
+}
+
+/// Render a source until it ends, or `max_seconds` have been generated.
+pub fn render_source<T>(mut source: T, max_seconds: f32) -> Vec<f32>
+where
+    T: GenSource,
+{
+    let mut samples = vec![0.; (max_seconds * sample_rate()) as usize];
+    let len = source.fill(&mut samples);
+    samples.truncate(len);
+    samples
+}
+
+/// Render a single `c` note of `instrument`, as `Phrase` would play it.
+pub fn render_note(instrument: &Instrument) -> Vec<f32> {
+    render_source(
+        instrument.play(Note::new(frequency_per_volt(0.2))),
+        MAX_RENDER_SECONDS,
+    )
+}
+
+/// Render a whole song, playing each chain once, then leaving voices to ring out.
+/// Notes start on the same samples as they would live.
+pub fn render_song(song: &Song) -> Vec<f32> {
+    let mut sequencer = Sequencer::play_through(song.clone());
+    let mut voices = Vec::<RawSource>::new();
+    let mut starting = Vec::new();
+    let mut samples = Vec::new();
+
+    while !sequencer.finished() {
+        let mut frames = 0;
+        while frames < BLOCK_SIZE && !sequencer.finished() {
+            // Not recycled, which notes get reused depends on the builder thread,
+            // and retriggered noise plays on from where it was, so renders would vary
+            sequencer.tick(|source, _, _| starting.push((frames, source)));
+            frames += 1;
+        }
+        mix_voices(&mut voices, &mut starting, &mut samples, frames);
+    }
+
+    let tail = (MAX_RENDER_SECONDS * sample_rate()) as usize;
+    let tail_end = samples.len() + tail;
+    while !voices.is_empty() && samples.len() < tail_end {
+        let start = samples.len();
+        let frames = (tail_end - start).min(BLOCK_SIZE);
+        let mixed = mix_voices(&mut voices, &mut starting, &mut samples, frames);
+        samples.truncate(start + mixed);
+    }
+
+    samples
+}
+
+/// Mix the next `frames` samples of every voice onto the end of `out`, dropping voices that end.
+/// `starting` voices start on their frame of the block, then join `voices`.
+/// Stereo voices are mixed down to mono.
+/// Returns frames up to the one the last voice ended on, or `frames` if any are still playing.
+fn mix_voices(
+    voices: &mut Vec<RawSource>,
+    starting: &mut Vec<(usize, RawSource)>,
+    out: &mut Vec<f32>,
+    frames: usize,
+) -> usize {
+    let start = out.len();
+    out.resize(start + frames, 0.);
+    let mix = &mut out[start..];
+    let mut buffer = [0.; BLOCK_SIZE * 2];
+    let mut last_end = 0;
+
+    let mut mix_voice = |voice: &mut RawSource, offset: usize| {
+        let mix = &mut mix[offset..];
+        let channels = voice.channels() as usize;
+        let len = voice.fill(&mut buffer[..mix.len() * channels]) / channels;
+        for (idx, sample) in mix[..len].iter_mut().enumerate() {
+            if channels == 2 {
+                *sample += (buffer[idx * 2] + buffer[idx * 2 + 1]) * 0.5;
+            } else {
+                *sample += buffer[idx];
+            }
+        }
+
+        let playing = len == mix.len();
+        if !playing {
+            last_end = last_end.max(offset + len + 1);
+        }
+        playing
+    };
+    voices.retain_mut(|voice| mix_voice(voice, 0));
+    for (offset, mut voice) in starting.drain(..) {
+        if mix_voice(&mut voice, offset) {
+            voices.push(voice);
+        }
+    }
+
+    if voices.is_empty() {
+        last_end.min(frames)
+    } else {
+        frames
+    }
+}
+
+/// Write interleaved samples at `sample_rate()` as a WAV stream.
+pub fn write_wav<W>(
+    mut writer: W,
+    samples: &[f32],
+    channels: u16,
+    format: WavFormat,
+) -> io::Result<()>
+where
+    W: Write,
+{
+    write_wav_header(&mut writer, samples.len(), channels, format)?;
+    write_wav_samples(&mut writer, samples, format)?;
+    writer.flush()
+}
+
+pub fn write_wav_file<P>(
+    path: P,
+    samples: &[f32],
+    channels: u16,
+    format: WavFormat,
+) -> io::Result<()>
+where
+    P: AsRef<Path>,
+{
+    write_wav(
+        BufWriter::new(File::create(path)?),
+        samples,
+        channels,
+        format,
+    )
+}
+
+/// Header for `samples` interleaved samples
+fn write_wav_header<W>(
+    writer: &mut W,
+    samples: usize,
+    channels: u16,
+    format: WavFormat,
+) -> io::Result<()>
+where
+    W: Write,
+{
+    let rate = sample_rate() as u32;
+    let block_align = channels * format.bits_per_sample() / 8;
+    let data_len = samples as u32 * format.bits_per_sample() as u32 / 8;
+
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&(36 + data_len).to_le_bytes())?;
+    writer.write_all(b"WAVE")?;
+
+    writer.write_all(b"fmt ")?;
+    writer.write_all(&16u32.to_le_bytes())?;
+    writer.write_all(&format.format_tag().to_le_bytes())?;
+    writer.write_all(&channels.to_le_bytes())?;
+    writer.write_all(&rate.to_le_bytes())?;
+    writer.write_all(&(rate * block_align as u32).to_le_bytes())?;
+    writer.write_all(&block_align.to_le_bytes())?;
+    writer.write_all(&format.bits_per_sample().to_le_bytes())?;
+
+    writer.write_all(b"data")?;
+    writer.write_all(&data_len.to_le_bytes())
+}
+
+fn write_wav_samples<W>(writer: &mut W, samples: &[f32], format: WavFormat) -> io::Result<()>
+where
+    W: Write,
+{
+    for sample in samples {
+        match format {
+            WavFormat::Int16 => {
+                let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+                writer.write_all(&sample.to_le_bytes())?;
+            }
+            WavFormat::Float32 => writer.write_all(&sample.to_le_bytes())?,
+        }
+    }
+    Ok(())
+}
+
+/// A WAV file written as the samples arrive, eg: while recording the game.
+/// The header is rewritten after each write, so the file is complete whenever the game stops.
+pub struct WavFileWriter {
+    writer: BufWriter<File>,
+    channels: u16,
+    format: WavFormat,
+    samples: usize,
+}
+
+impl WavFileWriter {
+    pub fn create<P>(path: P, channels: u16, format: WavFormat) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let mut writer = BufWriter::new(File::create(path)?);
+        write_wav_header(&mut writer, 0, channels, format)?;
+        writer.flush()?;
+        Ok(WavFileWriter {
+            writer,
+            channels,
+            format,
+            samples: 0,
+        })
+    }
+
+    /// Append interleaved samples
+    pub fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        write_wav_samples(&mut self.writer, samples, self.format)?;
+        self.samples += samples.len();
+
+        self.writer.seek(SeekFrom::Start(0))?;
+        write_wav_header(&mut self.writer, self.samples, self.channels, self.format)?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()
+    }
+}
+
+/// Headless entry point: `--render <song or instrument> <out.wav> [--float] [--rate <hz>]`
+///
+/// Renders at `DEFAULT_SAMPLE_RATE`, or `--rate` hz.
+///
+/// Songs are read from `.bjsong` files, or looked up with `song::song_by_name`.
+/// Instruments are looked up in the built in `Instruments`, with samples read from `assets/samples`,
+/// and rendered with `render_note`.
+pub fn render_cli(args: &[String]) -> io::Result<()> {
+    let usage = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "usage: --render <song or instrument> <out.wav> [--float] [--rate <hz>]",
+        )
+    };
+
+    let (Some(name), Some(path)) = (args.first(), args.get(1)) else {
+        return Err(usage());
+    };
+    let mut format = WavFormat::Int16;
+    let mut options = args[2..].iter();
+    while let Some(option) = options.next() {
+        match option.as_str() {
+            "--float" => format = WavFormat::Float32,
+            "--rate" => {
+                let rate = options.next().and_then(|rate| rate.parse().ok());
+                set_sample_rate(rate.ok_or_else(usage)?);
+            }
+            _ => return Err(usage()),
+        }
+    }
+
+    let instruments = Instruments::default();
+    // Sampler instruments are silent without their samples, not an error
+    let _ = instruments
+        .samples()
+        .load_dir(Path::new("assets"), "samples");
+    let samples = if name.ends_with(".bjsong") {
+        let song = song_file::parse_song(&std::fs::read(name)?, &instruments)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        render_song(&song)
+    } else if let Some(song) = song::song_by_name(name) {
+        render_song(&song)
+    } else if let Some(instrument) = instruments.get(name) {
+        render_note(&instrument)
+    } else {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no song or instrument named {}", name),
+        ));
+    };
+
+    write_wav_file(path, &samples, 1, format)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_u16(bytes: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes([bytes[at], bytes[at + 1]])
+    }
+
+    fn read_u32(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+    }
+
+    #[test]
+    fn wav_header_sizes() {
+        for (format, tag, bits) in [(WavFormat::Int16, 1, 16), (WavFormat::Float32, 3, 32)] {
+            let samples = [0.0, 0.5, -0.5, 0.25, 0.1, -0.1];
+            let mut bytes = Vec::new();
+            write_wav(&mut bytes, &samples, 2, format).unwrap();
+
+            let data_len = samples.len() * bits / 8;
+            let block_align = 2 * bits as u16 / 8;
+            let rate = sample_rate() as u32;
+            assert_eq!(bytes.len(), 44 + data_len);
+            assert_eq!(&bytes[0..4], b"RIFF");
+            assert_eq!(read_u32(&bytes, 4) as usize, bytes.len() - 8);
+            assert_eq!(&bytes[8..16], b"WAVEfmt ");
+            assert_eq!(read_u32(&bytes, 16), 16);
+            assert_eq!(read_u16(&bytes, 20), tag);
+            assert_eq!(read_u16(&bytes, 22), 2);
+            assert_eq!(read_u32(&bytes, 24), rate);
+            assert_eq!(read_u32(&bytes, 28), rate * block_align as u32);
+            assert_eq!(read_u16(&bytes, 32), block_align);
+            assert_eq!(read_u16(&bytes, 34), bits as u16);
+            assert_eq!(&bytes[36..40], b"data");
+            assert_eq!(read_u32(&bytes, 40) as usize, data_len);
+        }
+    }
+
+    #[test]
+    fn int16_samples_are_clamped() {
+        let mut bytes = Vec::new();
+        write_wav(
+            &mut bytes,
+            &[2.0, -2.0, 1.0, -1.0, 0.5, 0.0],
+            1,
+            WavFormat::Int16,
+        )
+        .unwrap();
+        let samples: Vec<i16> = bytes[44..]
+            .chunks_exact(2)
+            .map(|sample| i16::from_le_bytes([sample[0], sample[1]]))
+            .collect();
+        assert_eq!(samples, [32767, -32767, 32767, -32767, 16383, 0]);
+    }
+
+    /// Render `name` as `--render` would, and compare it to `tests/fixtures/<name>.wav`.
+    /// Set `BLESS_AUDIO=1` to rewrite the fixture after an intended change.
+    fn check_fixture(name: &str) {
+        let instrument = Instruments::default().get(name).unwrap();
+        let mut rendered = Vec::new();
+        write_wav(
+            &mut rendered,
+            &render_note(&instrument),
+            1,
+            WavFormat::Int16,
+        )
+        .unwrap();
+
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(format!("{}.wav", name));
+        if std::env::var_os("BLESS_AUDIO").is_some() {
+            std::fs::write(&path, &rendered).unwrap();
+        }
+        let fixture = std::fs::read(&path).unwrap();
+        assert!(
+            rendered == fixture,
+            "{} no longer matches {}, rerun with BLESS_AUDIO=1 if that's intended",
+            name,
+            path.display()
+        );
+    }
+
+    #[test]
+    fn kick_matches_fixture() {
+        check_fixture("kick");
+    }
+
+    #[test]
+    fn snare_matches_fixture() {
+        check_fixture("snare");
+    }
+
+    #[test]
+    fn supersaw_matches_fixture() {
+        check_fixture("supersaw");
+    }
+
+    #[test]
+    fn retriggered_notes_play_like_new_ones() {
+        let instruments = Instruments::default();
+        let assets = Path::new(env!("CARGO_MANIFEST_DIR")).join("assets");
+        instruments.samples().load_dir(&assets, "samples").unwrap();
+        let note = Note {
+            frequency: frequency_per_volt(0.2),
+            sustain: 0.25,
+        };
+        let samples = (MAX_RENDER_SECONDS * sample_rate()) as usize;
+        let render = |source: &mut RawSource, len| {
+            let mut out = vec![0.; len];
+            let len = source.fill(&mut out);
+            out.truncate(len);
+            out
+        };
+
+        // Not noise or strings, they're played with new noise each time
+        for name in [
+            "square_horn",
+            "kick",
+            "snare",
+            "drum",
+            "supersaw",
+            "warble",
+            "acid",
+            "pluck",
+            "chip",
+            "fuzz_bass",
+            "sample_drum",
+            "sample_hat",
+            "bell",
+            "fm_bass",
+            "metal_hit",
+            "marimba",
+            "gb_pulse",
+            "pwm_lead",
+            "cymbal",
+        ] {
+            let instrument = instruments.get(name).unwrap();
+            let new = render(&mut song::play_note(&instrument, note, 0.5), samples);
+            let mut source = song::play_note(&instrument, note, 0.5);
+            // Cut off part way, then once it's ended
+            render(&mut source, 1000);
+            for _ in 0..2 {
+                assert!(source.retrigger(), "{} can't be retriggered", name);
+                assert!(
+                    render(&mut source, samples) == new,
+                    "{} sounds different retriggered",
+                    name
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,274 @@
+//! Where the mix is played, behind `AudioBackend`.
+
+use super::audio_generator::*;
+use super::audio_output::MixStream;
+use super::audio_render::{WavFileWriter, WavFormat};
+use bevy::prelude::*;
+use rodio::cpal::traits::{DeviceTrait, HostTrait};
+use rodio::source::Source;
+use rodio::{OutputStream, OutputStreamHandle};
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Seconds without the device pulling any audio before it's treated as lost.
+const STALL_SECONDS: f32 = 1.0;
+/// How often the device's progress is counted.
+const STALL_CHECK: Duration = Duration::from_millis(50);
+
+/// Plays the mix, eg: on a sound card.
+/// Owned by the `AudioDevice`, which reopens it with a new stream if it closes.
+pub trait AudioBackend {
+    /// Rate the mix should be rendered at, `None` for the current `sample_rate()`
+    fn sample_rate(&self) -> Option<u32> {
+        None
+    }
+
+    /// Start playing `stream`
+    fn open(&mut self, stream: MixStream) -> io::Result<()>;
+
+    /// False until opened, or once the output has been lost
+    fn is_open(&self) -> bool;
+
+    /// Called once a frame, with the seconds since the last
+    fn update(&mut self, delta: f32);
+}
+
+/// Which `AudioBackend` the `AudioPlugin` plays through
+#[derive(Clone, Default)]
+pub enum Backend {
+    /// The default output device
+    #[default]
+    Rodio,
+    /// Nothing, the mix is kept going in time with the game
+    Null,
+    /// Everything played, recorded to a `CaptureBuffer`.
+    /// Only the tests check what was played so far.
+    #[cfg_attr(not(test), allow(dead_code))]
+    Capture(CaptureBuffer),
+    /// Everything played, recorded to a WAV file
+    CaptureFile(PathBuf),
+}
+
+impl Backend {
+    pub fn create(&self) -> Box<dyn AudioBackend> {
+        match self {
+            Backend::Rodio => Box::<RodioBackend>::default(),
+            Backend::Null => Box::<NullBackend>::default(),
+            Backend::Capture(buffer) => Box::new(CaptureBackend::new(buffer.clone())),
+            Backend::CaptureFile(path) => Box::new(CaptureBackend::to_file(path)),
+        }
+    }
+}
+
+/// Plays on the default output device.
+/// Closes if the device stops pulling audio, eg: when it's unplugged.
+#[derive(Default)]
+pub struct RodioBackend {
+    stream: Option<(OutputStream, OutputStreamHandle)>,
+    /// Counted up by the device as it plays
+    progress: Arc<AtomicU64>,
+    /// `progress` when last checked
+    last_progress: u64,
+    /// Seconds since the device last pulled any audio
+    stalled: f32,
+}
+
+impl AudioBackend for RodioBackend {
+    fn sample_rate(&self) -> Option<u32> {
+        // The device's own rate, so rodio doesn't resample the mix
+        let device = rodio::cpal::default_host().default_output_device()?;
+        let config = device.default_output_config().ok()?;
+        Some(config.sample_rate().0)
+    }
+
+    fn open(&mut self, stream: MixStream) -> io::Result<()> {
+        let (output, handle) =
+            OutputStream::try_default().map_err(|err| io::Error::other(err.to_string()))?;
+        let progress = self.progress.clone();
+        let stream = stream.periodic_access(STALL_CHECK, move |_| {
+            progress.fetch_add(1, Ordering::Relaxed);
+        });
+        handle
+            .play_raw(stream)
+            .map_err(|err| io::Error::other(err.to_string()))?;
+
+        self.stream = Some((output, handle));
+        self.last_progress = self.progress.load(Ordering::Relaxed);
+        self.stalled = 0.;
+        Ok(())
+    }
+
+    fn is_open(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    fn update(&mut self, delta: f32) {
+        if self.stream.is_none() {
+            return;
+        }
+
+        let progress = self.progress.load(Ordering::Relaxed);
+        if progress != self.last_progress {
+            self.last_progress = progress;
+            self.stalled = 0.;
+        } else {
+            self.stalled += delta;
+            if self.stalled > STALL_SECONDS {
+                warn!("Audio device stopped.");
+                self.stream = None;
+            }
+        }
+    }
+}
+
+/// Frames of the mix due each game frame, for backends that follow the game's clock
+#[derive(Default)]
+struct FrameClock {
+    fraction: f32,
+}
+
+impl FrameClock {
+    fn frames(&mut self, delta: f32) -> usize {
+        self.fraction += delta * sample_rate();
+        let frames = self.fraction as usize;
+        self.fraction -= frames as f32;
+        frames
+    }
+}
+
+/// Plays nowhere, for servers and tests.
+/// The mix is still pulled (and dropped) in time with the game, so songs advance.
+#[derive(Default)]
+pub struct NullBackend {
+    stream: Option<MixStream>,
+    clock: FrameClock,
+}
+
+impl AudioBackend for NullBackend {
+    fn open(&mut self, stream: MixStream) -> io::Result<()> {
+        self.stream = Some(stream);
+        Ok(())
+    }
+
+    fn is_open(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    fn update(&mut self, delta: f32) {
+        let frames = self.clock.frames(delta);
+        if let Some(stream) = &mut self.stream {
+            stream.by_ref().take(frames * 2).for_each(drop);
+        }
+    }
+}
+
+/// Interleaved stereo samples recorded by a `CaptureBackend`.
+/// Clones share the same recording, so one can be kept to check what was played.
+#[derive(Clone, Default)]
+pub struct CaptureBuffer {
+    samples: Arc<Mutex<Vec<f32>>>,
+}
+
+impl fmt::Debug for CaptureBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CaptureBuffer").finish_non_exhaustive()
+    }
+}
+
+#[cfg_attr(not(test), allow(dead_code))]
+impl CaptureBuffer {
+    /// Loudest sample recorded so far
+    pub fn peak(&self) -> f32 {
+        self.samples
+            .lock()
+            .unwrap()
+            .iter()
+            .fold(0.0_f32, |peak, sample| peak.max(sample.abs()))
+    }
+
+    pub fn clear(&self) {
+        self.samples.lock().unwrap().clear();
+    }
+}
+
+enum CaptureTarget {
+    Buffer(CaptureBuffer),
+    /// Created when opened
+    File(PathBuf, Option<WavFileWriter>),
+}
+
+/// Records everything played, in time with the game.
+pub struct CaptureBackend {
+    stream: Option<MixStream>,
+    clock: FrameClock,
+    target: CaptureTarget,
+    /// Samples pulled this frame
+    block: Vec<f32>,
+}
+
+impl CaptureBackend {
+    pub fn new(buffer: CaptureBuffer) -> Self {
+        Self::with_target(CaptureTarget::Buffer(buffer))
+    }
+
+    /// Record to a 16 bit WAV file, replacing any already there
+    pub fn to_file<P>(path: P) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        Self::with_target(CaptureTarget::File(path.as_ref().to_path_buf(), None))
+    }
+
+    fn with_target(target: CaptureTarget) -> Self {
+        CaptureBackend {
+            stream: None,
+            clock: FrameClock::default(),
+            target,
+            block: Vec::new(),
+        }
+    }
+}
+
+impl AudioBackend for CaptureBackend {
+    fn open(&mut self, stream: MixStream) -> io::Result<()> {
+        if let CaptureTarget::File(path, writer @ None) = &mut self.target {
+            *writer = Some(WavFileWriter::create(path, 2, WavFormat::Int16)?);
+        }
+        self.stream = Some(stream);
+        Ok(())
+    }
+
+    fn is_open(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    fn update(&mut self, delta: f32) {
+        let frames = self.clock.frames(delta);
+        let Some(stream) = &mut self.stream else {
+            return;
+        };
+
+        self.block.clear();
+        self.block.extend(stream.by_ref().take(frames * 2));
+        match &mut self.target {
+            CaptureTarget::Buffer(buffer) => {
+                buffer
+                    .samples
+                    .lock()
+                    .unwrap()
+                    .extend_from_slice(&self.block);
+            }
+            CaptureTarget::File(path, Some(writer)) => {
+                if let Err(err) = writer.write(&self.block) {
+                    warn!("Couldn't write audio to {}: {}", path.display(), err);
+                    self.stream = None;
+                }
+            }
+            CaptureTarget::File(_, None) => {}
+        }
+    }
+}
@@ -0,0 +1,424 @@
+use super::audio_generator::*;
+use serde::Deserialize;
+
+/// Processes a signal a sample at a time, with its own state.
+pub trait Effect: Send + Sync + 'static {
+    fn process(&mut self, input: f32) -> f32;
+
+    /// Seconds the effect rings on for after its input goes silent
+    fn tail(&self) -> f32;
+
+    /// Silence it, as it was when made
+    fn reset(&mut self);
+}
+
+/// Level an echo or reverb has to fall to before its tail is over
+const SILENCE: f32 = 0.001;
+
+/// Seconds for a loop of `period` seconds with `feedback` to fall to `SILENCE`
+fn decay_time(period: f32, feedback: f32) -> f32 {
+    if feedback <= 0. {
+        period
+    } else {
+        period * (SILENCE.ln() / feedback.ln()).min(1000.)
+    }
+}
+
+/// Longest `Delay` in seconds, longer ones are shortened to this
+pub const MAX_DELAY: f32 = 4.0;
+
+/// Echoes, each `feedback` times quieter than the last
+pub struct Delay {
+    buffer: Vec<f32>,
+    position: usize,
+    feedback: f32,
+    /// 0.0 (dry) to 1.0 (only echoes)
+    mix: f32,
+}
+
+impl Delay {
+    pub fn new(seconds: f32, feedback: f32, mix: f32) -> Self {
+        let seconds = seconds.clamp(0., MAX_DELAY);
+        let length = ((seconds * sample_rate()) as usize).max(1);
+        Delay {
+            buffer: vec![0.; length],
+            position: 0,
+            feedback: feedback.clamp(0., 0.95),
+            mix: mix.clamp(0., 1.),
+        }
+    }
+
+    /// Delay of `sixteenths` 16th notes, from a `Song::step_time`
+    pub fn synced(sixteenths: f32, step_time: f32, feedback: f32, mix: f32) -> Self {
+        Self::new(sixteenths * step_time, feedback, mix)
+    }
+}
+
+impl Effect for Delay {
+    fn process(&mut self, input: f32) -> f32 {
+        let echo = self.buffer[self.position];
+        self.buffer[self.position] = input + echo * self.feedback;
+        self.position = (self.position + 1) % self.buffer.len();
+        input * (1. - self.mix) + echo * self.mix
+    }
+
+    fn tail(&self) -> f32 {
+        decay_time(self.buffer.len() as f32 / sample_rate(), self.feedback)
+    }
+
+    fn reset(&mut self) {
+        self.buffer.fill(0.);
+        self.position = 0;
+    }
+}
+
+/// Seconds a chorus voice is delayed by, before modulation
+const CHORUS_DELAY: f32 = 0.015;
+
+/// A copy of the signal with a wobbling delay, mixed back in
+pub struct Chorus {
+    buffer: Vec<f32>,
+    position: usize,
+    /// In hz
+    rate: f32,
+    /// Seconds the delay wobbles by
+    depth: f32,
+    phase: f32,
+    mix: f32,
+}
+
+impl Chorus {
+    pub fn new(rate: f32, depth: f32, mix: f32) -> Self {
+        let depth = depth.clamp(0., CHORUS_DELAY);
+        let length = ((CHORUS_DELAY + depth) * sample_rate()) as usize + 2;
+        Chorus {
+            buffer: vec![0.; length],
+            position: 0,
+            rate,
+            depth,
+            phase: 0.,
+            mix: mix.clamp(0., 1.),
+        }
+    }
+}
+
+impl Default for Chorus {
+    fn default() -> Self {
+        Chorus::new(0.8, 0.003, 0.5)
+    }
+}
+
+impl Effect for Chorus {
+    fn process(&mut self, input: f32) -> f32 {
+        let length = self.buffer.len();
+        self.buffer[self.position] = input;
+
+        let delay = (CHORUS_DELAY + self.depth * (self.phase * std::f32::consts::TAU).sin())
+            * sample_rate();
+        self.phase = (self.phase + self.rate / sample_rate()) % 1.0;
+
+        // Linear interpolation between the two samples either side of the delay
+        let read = (self.position + length) as f32 - delay;
+        let index = read.floor() as usize;
+        let fraction = read.fract();
+        let a = self.buffer[index % length];
+        let b = self.buffer[(index + 1) % length];
+        let delayed = a + (b - a) * fraction;
+
+        self.position = (self.position + 1) % length;
+        input * (1. - self.mix) + delayed * self.mix
+    }
+
+    fn tail(&self) -> f32 {
+        CHORUS_DELAY + self.depth
+    }
+
+    fn reset(&mut self) {
+        self.buffer.fill(0.);
+        self.position = 0;
+        self.phase = 0.;
+    }
+}
+
+/// Feedback comb filter, with a lowpass in the loop
+struct Comb {
+    buffer: Vec<f32>,
+    position: usize,
+    filtered: f32,
+}
+
+impl Comb {
+    fn new(length: usize) -> Self {
+        Comb {
+            buffer: vec![0.; length],
+            position: 0,
+            filtered: 0.,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.buffer.fill(0.);
+        self.position = 0;
+        self.filtered = 0.;
+    }
+
+    fn process(&mut self, input: f32, feedback: f32, damping: f32) -> f32 {
+        let output = self.buffer[self.position];
+        self.filtered = output * (1. - damping) + self.filtered * damping;
+        self.buffer[self.position] = input + self.filtered * feedback;
+        self.position = (self.position + 1) % self.buffer.len();
+        output
+    }
+}
+
+struct Allpass {
+    buffer: Vec<f32>,
+    position: usize,
+}
+
+impl Allpass {
+    fn new(length: usize) -> Self {
+        Allpass {
+            buffer: vec![0.; length],
+            position: 0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.buffer.fill(0.);
+        self.position = 0;
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let buffered = self.buffer[self.position];
+        self.buffer[self.position] = input + buffered * 0.5;
+        self.position = (self.position + 1) % self.buffer.len();
+        buffered - input
+    }
+}
+
+/// Comb and allpass lengths in samples at 44100hz, from Freeverb
+const COMB_LENGTHS: [usize; 4] = [1116, 1188, 1277, 1356];
+const ALLPASS_LENGTHS: [usize; 2] = [556, 441];
+
+/// Small Schroeder style reverb: parallel combs into series allpasses
+pub struct Reverb {
+    combs: Vec<Comb>,
+    allpasses: Vec<Allpass>,
+    feedback: f32,
+    damping: f32,
+    mix: f32,
+}
+
+impl Reverb {
+    /// `room_size` and `damping` are 0.0 to 1.0
+    pub fn new(room_size: f32, damping: f32, mix: f32) -> Self {
+        let scale = sample_rate() / 44100.;
+        let length = |samples: usize| ((samples as f32 * scale) as usize).max(1);
+        Reverb {
+            combs: COMB_LENGTHS.iter().map(|&l| Comb::new(length(l))).collect(),
+            allpasses: ALLPASS_LENGTHS
+                .iter()
+                .map(|&l| Allpass::new(length(l)))
+                .collect(),
+            feedback: 0.7 + room_size.clamp(0., 1.) * 0.28,
+            damping: damping.clamp(0., 1.),
+            mix: mix.clamp(0., 1.),
+        }
+    }
+}
+
+impl Default for Reverb {
+    fn default() -> Self {
+        Reverb::new(0.5, 0.5, 0.3)
+    }
+}
+
+impl Effect for Reverb {
+    fn process(&mut self, input: f32) -> f32 {
+        // Quieter in, the combs add up
+        let input_gain = 0.015 * COMB_LENGTHS.len() as f32;
+        let mut wet = 0.;
+        for comb in &mut self.combs {
+            wet += comb.process(input * input_gain, self.feedback, self.damping);
+        }
+        for allpass in &mut self.allpasses {
+            wet = allpass.process(wet);
+        }
+        input * (1. - self.mix) + wet * self.mix
+    }
+
+    fn tail(&self) -> f32 {
+        let longest = COMB_LENGTHS[COMB_LENGTHS.len() - 1] as f32 / 44100.;
+        decay_time(longest, self.feedback)
+    }
+
+    fn reset(&mut self) {
+        self.combs.iter_mut().for_each(Comb::reset);
+        self.allpasses.iter_mut().for_each(Allpass::reset);
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub enum Shape {
+    /// `tanh` saturation, rounds off peaks
+    Soft,
+    /// Clips at -1.0 and 1.0
+    Hard,
+    /// Folds peaks back down, bright and buzzy
+    Fold,
+}
+
+/// Drives the signal through a `Shape`, for saturation and distortion
+pub struct Waveshaper {
+    shape: Shape,
+    /// Gain into the shape
+    drive: f32,
+    /// Gain after the shape
+    level: f32,
+}
+
+impl Waveshaper {
+    pub fn new(shape: Shape, drive: f32, level: f32) -> Self {
+        Waveshaper {
+            shape,
+            drive: drive.max(0.),
+            level,
+        }
+    }
+}
+
+impl Effect for Waveshaper {
+    fn process(&mut self, input: f32) -> f32 {
+        let x = input * self.drive;
+        let shaped = match self.shape {
+            Shape::Soft => x.tanh(),
+            Shape::Hard => x.clamp(-1., 1.),
+            // Triangle of period 4 passes -1.0..1.0 straight through
+            Shape::Fold => 1. - ((x + 1.).rem_euclid(4.) - 2.).abs(),
+        };
+        shaped * self.level
+    }
+
+    fn tail(&self) -> f32 {
+        0.
+    }
+
+    fn reset(&mut self) {}
+}
+
+/// Lo-fi: fewer bits per sample, and samples held for longer
+pub struct Bitcrusher {
+    /// Steps either side of 0.0
+    steps: f32,
+    /// Samples each crushed sample is held for
+    downsample: f32,
+    phase: f32,
+    held: f32,
+}
+
+impl Bitcrusher {
+    /// `bits` is 1 to 24, `downsample` divides the sample rate
+    pub fn new(bits: u32, downsample: f32) -> Self {
+        Bitcrusher {
+            steps: 2_f32.powi(bits.clamp(1, 24) as i32 - 1),
+            downsample: downsample.max(1.),
+            phase: 0.,
+            held: 0.,
+        }
+    }
+}
+
+impl Effect for Bitcrusher {
+    fn process(&mut self, input: f32) -> f32 {
+        self.phase -= 1.;
+        if self.phase < 0. {
+            self.phase += self.downsample;
+            self.held = (input * self.steps).round() / self.steps;
+        }
+        self.held
+    }
+
+    fn tail(&self) -> f32 {
+        0.
+    }
+
+    fn reset(&mut self) {
+        self.phase = 0.;
+        self.held = 0.;
+    }
+}
+
+/// Runs a source through an effect, ringing on for the effect's tail once the source ends.
+pub struct Fx<T: GenSource, E: Effect> {
+    source: T,
+    effect: E,
+    /// Samples of tail left, once the source has ended
+    tail: Option<usize>,
+}
+
+impl<T, E> GenSource for Fx<T, E>
+where
+    T: GenSource,
+    E: Effect,
+{
+    fn fill(&mut self, out: &mut [f32]) -> usize {
+        let mut rendered = 0;
+        if self.tail.is_none() {
+            rendered = self.source.fill(out);
+            for sample in &mut out[..rendered] {
+                *sample = self.effect.process(*sample);
+            }
+            if rendered == out.len() {
+                return rendered;
+            }
+            self.tail = Some((self.effect.tail() * sample_rate()) as usize);
+        }
+
+        let Some(tail) = &mut self.tail else {
+            return rendered;
+        };
+        let len = (out.len() - rendered).min(*tail);
+        *tail -= len;
+        for sample in &mut out[rendered..rendered + len] {
+            *sample = self.effect.process(0.);
+        }
+        rendered + len
+    }
+
+    fn retrigger(&mut self) -> bool {
+        self.effect.reset();
+        self.tail = None;
+        self.source.retrigger()
+    }
+}
+
+impl<T, E> Fx<T, E>
+where
+    T: GenSource,
+    E: Effect,
+{
+    pub fn new(source: T, effect: E) -> Self {
+        Fx {
+            source,
+            effect,
+            tail: None,
+        }
+    }
+
+    pub fn into_raw(self) -> RawSource {
+        RawSource::new(self)
+    }
+}
+
+impl<T, E> Iterator for Fx<T, E>
+where
+    T: GenSource,
+    E: Effect,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        next_sample(self)
+    }
+}
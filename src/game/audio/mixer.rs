@@ -0,0 +1,417 @@
+use super::analysis::{Analysis, Meter};
+use super::audio_generator::*;
+use super::effects::{Chorus, Delay, Effect, Reverb};
+use super::sequencer::{PoolId, Sequencer};
+use super::voice::{VoiceCategory, VoiceLimits, VoiceManager};
+use rodio::source::Source;
+use serde::Deserialize;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Frames rendered per lock of the mixer settings.
+const BLOCK_FRAMES: usize = 256;
+
+/// Level the limiter holds the master output under.
+const LIMITER_THRESHOLD: f32 = 0.9;
+/// Recovery of the limiter gain per second, once the peak has passed.
+const LIMITER_RELEASE: f32 = 8.82;
+
+/// Where a source is mixed.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Bus {
+    /// A `Song` track, by index
+    Track(usize),
+    Sfx,
+}
+
+impl Bus {
+    pub fn category(&self) -> VoiceCategory {
+        match self {
+            Bus::Track(_) => VoiceCategory::Music,
+            Bus::Sfx => VoiceCategory::Sfx,
+        }
+    }
+}
+
+/// Levels a bus sends to the shared effects, after its gain.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Sends {
+    pub delay: f32,
+    pub chorus: f32,
+    pub reverb: f32,
+}
+
+/// Gain, stereo position and effect sends of a bus.
+#[derive(Clone, Copy, Debug)]
+pub struct ChannelStrip {
+    pub gain: f32,
+    /// -1.0 (left) to 1.0 (right)
+    pub pan: f32,
+    pub sends: Sends,
+}
+
+impl Default for ChannelStrip {
+    fn default() -> Self {
+        ChannelStrip {
+            gain: 1.0,
+            pan: 0.0,
+            sends: Sends::default(),
+        }
+    }
+}
+
+impl ChannelStrip {
+    /// Left and right gain, constant power pan
+    fn gains(&self) -> (f32, f32) {
+        let (left, right) = pan_gains(self.pan);
+        (self.gain * left, self.gain * right)
+    }
+}
+
+#[derive(Clone)]
+struct MixerSettings {
+    tracks: Vec<ChannelStrip>,
+    sfx: ChannelStrip,
+    master: f32,
+    muted: bool,
+}
+
+impl MixerSettings {
+    fn strip_mut(&mut self, bus: Bus) -> &mut ChannelStrip {
+        match bus {
+            Bus::Track(track) => {
+                if track >= self.tracks.len() {
+                    self.tracks.resize(track + 1, ChannelStrip::default());
+                }
+                &mut self.tracks[track]
+            }
+            Bus::Sfx => &mut self.sfx,
+        }
+    }
+}
+
+enum MixerCommand {
+    Play(RawSource, Bus),
+    /// Replaces the playing sequencer
+    Sequence(Box<Sequencer>),
+    /// Replaces the delay effect, eg: when the tempo changes
+    Delay(Box<Delay>),
+    VoiceLimits(VoiceLimits),
+}
+
+/// A frame of the block being mixed, summed over the voices
+#[derive(Clone, Copy, Default)]
+struct MixFrame {
+    left: f32,
+    right: f32,
+    sends: Sends,
+    /// Mono level of the sfx bus
+    sfx: f32,
+}
+
+/// Effects every bus can send to, played back in the centre
+struct SendEffects {
+    delay: Delay,
+    chorus: Chorus,
+    reverb: Reverb,
+}
+
+impl SendEffects {
+    /// Mono wet signal for a frame of send levels
+    fn process(&mut self, input: Sends) -> f32 {
+        self.delay.process(input.delay)
+            + self.chorus.process(input.chorus)
+            + self.reverb.process(input.reverb)
+    }
+}
+
+/// Handle to the mix, sends sources to a `MixerSource` and sets its levels.
+#[derive(Clone)]
+pub struct Mixer {
+    sender: Sender<MixerCommand>,
+    settings: Arc<Mutex<MixerSettings>>,
+    meter: Arc<Mutex<Meter>>,
+}
+
+impl Mixer {
+    /// Create a mixer, and the source to hand to the output.
+    pub fn new() -> (Self, MixerSource) {
+        Self::with_voices(VoiceManager::default())
+    }
+
+    /// Create a mixer with custom voice limits.
+    pub fn with_voices(voice_manager: VoiceManager) -> (Self, MixerSource) {
+        let (sender, receiver) = channel();
+        let settings = Arc::new(Mutex::new(MixerSettings {
+            tracks: Vec::new(),
+            sfx: ChannelStrip::default(),
+            master: 0.8,
+            muted: false,
+        }));
+
+        let meter = Arc::new(Mutex::new(Meter::default()));
+
+        let mixer = Mixer {
+            sender,
+            settings: settings.clone(),
+            meter: meter.clone(),
+        };
+        let source = MixerSource {
+            receiver,
+            settings,
+            voice_manager,
+            sequencer: None,
+            // Sends are fully wet, the dry signal goes straight to the mix
+            effects: SendEffects {
+                delay: Delay::new(0.375, 0.4, 1.0),
+                chorus: Chorus::new(0.8, 0.003, 1.0),
+                reverb: Reverb::new(0.5, 0.5, 1.0),
+            },
+            notes: Vec::new(),
+            frames: vec![MixFrame::default(); BLOCK_FRAMES],
+            limiter_gain: 1.0,
+            meter,
+            block_meter: Meter::default(),
+            track_levels: Vec::new(),
+            buffer: Vec::with_capacity(BLOCK_FRAMES * 2),
+            position: 0,
+        };
+        (mixer, source)
+    }
+
+    pub fn play(&self, source: RawSource, bus: Bus) {
+        self.send(MixerCommand::Play(source, bus));
+    }
+
+    /// Play a song, its notes are started on the audio thread.
+    pub fn play_sequence(&self, sequencer: Sequencer) {
+        self.send(MixerCommand::Sequence(Box::new(sequencer)));
+    }
+
+    /// Delay every bus sends to, usually synced to the song
+    pub fn set_delay(&self, delay: Delay) {
+        self.send(MixerCommand::Delay(Box::new(delay)));
+    }
+
+    /// Polyphony and voice stealing, from the next voice started
+    pub fn set_voice_limits(&self, limits: VoiceLimits) {
+        self.send(MixerCommand::VoiceLimits(limits));
+    }
+
+    fn send(&self, command: MixerCommand) {
+        // Only fails once the output has been dropped, then there's nothing to play to.
+        let _ = self.sender.send(command);
+    }
+
+    pub fn set_gain(&self, bus: Bus, gain: f32) {
+        self.settings.lock().unwrap().strip_mut(bus).gain = gain.max(0.0);
+    }
+
+    pub fn set_pan(&self, bus: Bus, pan: f32) {
+        self.settings.lock().unwrap().strip_mut(bus).pan = pan.clamp(-1.0, 1.0);
+    }
+
+    pub fn set_sends(&self, bus: Bus, sends: Sends) {
+        self.settings.lock().unwrap().strip_mut(bus).sends = sends;
+    }
+
+    /// Silence the master output, leaving its gain as it was
+    pub fn set_muted(&self, muted: bool) {
+        self.settings.lock().unwrap().muted = muted;
+    }
+
+    pub fn muted(&self) -> bool {
+        self.settings.lock().unwrap().muted
+    }
+
+    /// Update `analysis` with the levels played since the last call
+    pub fn analyse(&self, analysis: &mut Analysis) {
+        self.meter.lock().unwrap().read(analysis);
+    }
+}
+
+/// Stereo mix of every playing source, ending in a limiter and soft clip.
+/// Never ends, played once on the output.
+pub struct MixerSource {
+    receiver: Receiver<MixerCommand>,
+    settings: Arc<Mutex<MixerSettings>>,
+    voice_manager: VoiceManager,
+    sequencer: Option<Box<Sequencer>>,
+    effects: SendEffects,
+    /// Notes the sequencer starts this block, by frame
+    notes: Vec<(usize, RawSource, Bus, PoolId)>,
+    frames: Vec<MixFrame>,
+    limiter_gain: f32,
+    /// Shared with the `Mixer`, updated once per block
+    meter: Arc<Mutex<Meter>>,
+    block_meter: Meter,
+    /// Each track's mono mix for each frame of the block
+    track_levels: Vec<[f32; BLOCK_FRAMES]>,
+
+    /// Interleaved left/right samples
+    buffer: Vec<f32>,
+    position: usize,
+}
+
+impl MixerSource {
+    fn render_block(&mut self) {
+        while let Ok(command) = self.receiver.try_recv() {
+            match command {
+                MixerCommand::Play(source, bus) => self.voice_manager.start(source, bus, None),
+                MixerCommand::Sequence(sequencer) => self.sequencer = Some(sequencer),
+                MixerCommand::Delay(delay) => self.effects.delay = *delay,
+                MixerCommand::VoiceLimits(limits) => self.voice_manager.limits = limits,
+            }
+        }
+        let settings = self.settings.lock().unwrap().clone();
+        let master = if settings.muted { 0.0 } else { settings.master };
+
+        if let Some(sequencer) = &mut self.sequencer {
+            let notes = &mut self.notes;
+            for frame in 0..BLOCK_FRAMES {
+                sequencer.tick(|source, track, pool| {
+                    notes.push((frame, source, Bus::Track(track), pool))
+                });
+            }
+            if sequencer.finished() {
+                self.sequencer = None;
+            }
+        }
+
+        self.frames.fill(MixFrame::default());
+        if self.track_levels.len() < settings.tracks.len() {
+            self.track_levels
+                .resize(settings.tracks.len(), [0.; BLOCK_FRAMES]);
+        }
+        self.track_levels
+            .iter_mut()
+            .for_each(|levels| levels.fill(0.));
+
+        // Voices are rendered up to each note, so notes can steal voices mid block
+        let mut notes = std::mem::take(&mut self.notes);
+        let mut notes_iter = notes.drain(..).peekable();
+        let mut start = 0;
+        while start < BLOCK_FRAMES {
+            while let Some((_, source, bus, pool)) =
+                notes_iter.next_if(|(frame, ..)| *frame == start)
+            {
+                self.voice_manager.start(source, bus, Some(pool));
+            }
+            let end = notes_iter.peek().map_or(BLOCK_FRAMES, |(frame, ..)| *frame);
+            self.mix_voices(&settings, start, end);
+            start = end;
+        }
+        drop(notes_iter);
+        self.notes = notes;
+
+        // Ended notes go back to be played again, once the song is over they're just dropped
+        for (pool, source) in self.voice_manager.ended() {
+            if let Some(sequencer) = &self.sequencer {
+                sequencer.recycle(pool, source);
+            }
+        }
+
+        self.buffer.clear();
+        self.position = 0;
+        for (idx, frame) in self.frames.iter().enumerate() {
+            for (track, levels) in self.track_levels.iter().enumerate() {
+                self.block_meter.add(Bus::Track(track), levels[idx]);
+            }
+            self.block_meter.add(Bus::Sfx, frame.sfx);
+
+            let wet = self.effects.process(frame.sends);
+            let left = (frame.left + wet) * master;
+            let right = (frame.right + wet) * master;
+
+            // Pull the gain down instantly on peaks, recover slowly.
+            let peak = left.abs().max(right.abs()) * self.limiter_gain;
+            if peak > LIMITER_THRESHOLD {
+                self.limiter_gain *= LIMITER_THRESHOLD / peak;
+            } else {
+                self.limiter_gain = (self.limiter_gain + LIMITER_RELEASE / sample_rate()).min(1.0);
+            }
+
+            let (left, right) = (
+                (left * self.limiter_gain).tanh(),
+                (right * self.limiter_gain).tanh(),
+            );
+            self.block_meter.add_master(left, right);
+            self.buffer.push(left);
+            self.buffer.push(right);
+        }
+
+        self.meter.lock().unwrap().merge(&mut self.block_meter);
+    }
+
+    /// Add the voices from frame `start` to `end` of the block to `frames` and `track_levels`
+    fn mix_voices(&mut self, settings: &MixerSettings, start: usize, end: usize) {
+        let frames = &mut self.frames[start..end];
+        let track_levels = &mut self.track_levels;
+        self.voice_manager
+            .render(end - start, |bus, channels, samples| {
+                let strip = match bus {
+                    Bus::Track(track) => settings.tracks.get(track).copied().unwrap_or_default(),
+                    Bus::Sfx => settings.sfx,
+                };
+                let (gain_left, gain_right) = strip.gains();
+                for (idx, (mix, frame)) in frames
+                    .iter_mut()
+                    .zip(samples.chunks_exact(channels))
+                    .enumerate()
+                {
+                    // Stereo voices are panned again by their bus
+                    let (sample, sample_right) = (frame[0], frame[channels - 1]);
+                    mix.left += sample * gain_left;
+                    mix.right += sample_right * gain_right;
+
+                    let mono = (sample + sample_right) * 0.5 * strip.gain;
+                    mix.sends.delay += mono * strip.sends.delay;
+                    mix.sends.chorus += mono * strip.sends.chorus;
+                    mix.sends.reverb += mono * strip.sends.reverb;
+
+                    match bus {
+                        Bus::Track(track) => {
+                            if track >= track_levels.len() {
+                                track_levels.resize(track + 1, [0.; BLOCK_FRAMES]);
+                            }
+                            track_levels[track][start + idx] += mono;
+                        }
+                        Bus::Sfx => mix.sfx += mono,
+                    }
+                }
+            });
+    }
+}
+
+impl Source for MixerSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        2
+    }
+
+    fn sample_rate(&self) -> u32 {
+        sample_rate() as u32
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+impl Iterator for MixerSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.position >= self.buffer.len() {
+            self.render_block();
+        }
+        let sample = self.buffer[self.position];
+        self.position += 1;
+        Some(sample)
+    }
+}
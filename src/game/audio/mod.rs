@@ -1,25 +1,60 @@
+use self::analysis::{analysis_system, Analysis};
 use self::audio_generator::*;
-use self::audio_output::{play_queued_audio_system, AudioOutput};
+use self::audio_output::{
+    audio_device_system, pause_unfocused_system, play_queued_audio_system, AudioDevice, AudioOutput,
+};
+use self::backend::Backend;
+use self::mixer::Bus;
+use self::voice::VoiceLimits;
 use bevy::prelude::*;
+use bevy::window::WindowFocused;
 use std::collections::VecDeque;
 use std::sync::RwLock;
 
+pub mod analysis;
 pub mod audio_generator;
 pub mod audio_output;
+pub mod audio_render;
+pub mod backend;
+pub mod effects;
+pub mod mixer;
+pub mod patch_graph;
+pub mod sampler;
+pub mod sequencer;
+pub mod voice;
 
-pub struct AudioPlugin;
+#[derive(Clone, Default)]
+pub struct AudioPlugin {
+    pub backend: Backend,
+    pub voices: VoiceLimits,
+}
 
 impl bevy::app::Plugin for AudioPlugin {
     fn build(&self, app: &mut App) {
-        app.insert_resource(AudioOutput::default())
+        let backend = self.backend.create();
+        // Before anything is created at the old rate
+        if let Some(rate) = backend.sample_rate() {
+            set_sample_rate(rate);
+        }
+        let audio_output = AudioOutput::default();
+        audio_output.mixer.set_voice_limits(self.voices);
+        let device = AudioDevice::open(backend, &audio_output);
+        app.insert_resource(audio_output)
+            .insert_non_send_resource(device)
             .insert_resource(Audio::default())
+            .init_resource::<Analysis>()
+            // Without a window, eg: on a server
+            .add_event::<WindowFocused>()
             .add_startup_system(audio_startup)
-            .add_system(play_queued_audio_system);
+            .add_system(play_queued_audio_system)
+            .add_system(audio_device_system.after(play_queued_audio_system))
+            .add_system(pause_unfocused_system)
+            .add_system(analysis_system.after(audio_device_system));
     }
 }
 
-fn audio_startup(audio_output: ResMut<AudioOutput>) {
-    if let Some(stream_handle) = &audio_output.stream_handle {
+fn audio_startup(device: NonSend<AudioDevice>, audio: Res<Audio>) {
+    if device.is_open() {
         println!("here");
         let vco = Vco::new(
             //SawWave::new(440.),
@@ -28,13 +63,13 @@ fn audio_startup(audio_output: ResMut<AudioOutput>) {
             Attenuator::new(SawWave::new(10.), 0.1),
         );
         let vca = Vca::new(vco, Envelope::new(0.2, 0.1, 0.2, 1.0));
-        stream_handle.play_raw(vca.as_raw()).unwrap();
+        audio.play(vca.into_raw());
     }
 }
 
 #[derive(Resource)]
 pub struct Audio {
-    pub(crate) queue: RwLock<VecDeque<RawSource>>,
+    pub(crate) queue: RwLock<VecDeque<(RawSource, Bus)>>,
 }
 
 impl Default for Audio {
@@ -46,7 +81,12 @@ impl Default for Audio {
 }
 
 impl Audio {
+    /// Play a sound effect
     pub fn play(&self, source: RawSource) {
-        self.queue.write().unwrap().push_back(source);
+        self.play_on(source, Bus::Sfx);
+    }
+
+    pub fn play_on(&self, source: RawSource, bus: Bus) {
+        self.queue.write().unwrap().push_back((source, bus));
     }
 }
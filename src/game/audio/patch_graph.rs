@@ -0,0 +1,308 @@
+use super::audio_generator::*;
+use serde::Deserialize;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+/// A module input that outputs can be routed to.
+/// Every connection to an input is summed.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize)]
+pub enum Input {
+    /// Signal to process, for filters and VCAs
+    Audio,
+    /// Volts added to an oscillator's pitch
+    Pitch,
+    /// Volts added to a filter's cutoff
+    Cutoff,
+    /// Added to a filter's resonance
+    Resonance,
+    /// Added to a VCA's gain
+    Gain,
+}
+
+const INPUTS: usize = 5;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct NodeId(usize);
+
+/// Plays the last value set on it, to feed graph inputs to a module
+#[derive(Clone)]
+struct Port(Arc<AtomicU32>);
+
+impl GenSource for Port {
+    fn fill(&mut self, out: &mut [f32]) -> usize {
+        out.fill(f32::from_bits(self.0.load(Ordering::Relaxed)));
+        out.len()
+    }
+
+    /// Set before each sample it plays, so there's nothing to restart
+    fn retrigger(&mut self) -> bool {
+        true
+    }
+}
+
+impl Port {
+    fn new() -> Self {
+        Port(Arc::new(AtomicU32::new(0)))
+    }
+
+    fn set(&self, value: f32) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+}
+
+impl Iterator for Port {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        next_sample(self)
+    }
+}
+
+enum Module {
+    Source(RawSource),
+    Oscillator {
+        vco: Vco<Box<dyn Oscillator>, Port>,
+        pitch: Port,
+    },
+    Filter {
+        svf: Svf<Port>,
+        audio: Port,
+        /// In volts
+        cutoff: f32,
+        resonance: f32,
+        last_cutoff: f32,
+    },
+    Vca {
+        vca: Vca<Port, Port>,
+        audio: Port,
+        gain_port: Port,
+        gain: f32,
+    },
+}
+
+impl Module {
+    /// `None` once the module has ended
+    fn process(&mut self, inputs: &[f32; INPUTS]) -> Option<f32> {
+        match self {
+            Module::Source(source) => source.next(),
+            Module::Oscillator { vco, pitch } => {
+                pitch.set(inputs[Input::Pitch as usize]);
+                vco.next()
+            }
+            Module::Filter {
+                svf,
+                audio,
+                cutoff,
+                resonance,
+                last_cutoff,
+            } => {
+                audio.set(inputs[Input::Audio as usize]);
+                let volts = *cutoff + inputs[Input::Cutoff as usize];
+                // Recalculating the coefficients is slow, only do it on change
+                if volts != *last_cutoff {
+                    *last_cutoff = volts;
+                    svf.set_frequency(frequency_per_volt(volts));
+                }
+                svf.set_resonance(*resonance + inputs[Input::Resonance as usize]);
+                svf.next()
+            }
+            Module::Vca {
+                vca,
+                audio,
+                gain_port,
+                gain,
+            } => {
+                audio.set(inputs[Input::Audio as usize]);
+                gain_port.set(*gain + inputs[Input::Gain as usize]);
+                vca.next()
+            }
+        }
+    }
+
+    fn retrigger(&mut self) -> bool {
+        match self {
+            Module::Source(source) => source.retrigger(),
+            Module::Oscillator { vco, .. } => vco.retrigger(),
+            Module::Filter {
+                svf,
+                cutoff,
+                resonance,
+                last_cutoff,
+                ..
+            } => {
+                *last_cutoff = *cutoff;
+                svf.set_frequency(frequency_per_volt(*cutoff));
+                svf.set_resonance(*resonance);
+                svf.retrigger()
+            }
+            Module::Vca { vca, .. } => vca.retrigger(),
+        }
+    }
+}
+
+/// Modules wired together at runtime, any output can be routed to any input.
+/// Modules run in the order they were added, so an input from a later
+/// module (eg: feedback) gets its previous sample.
+pub struct PatchGraph {
+    modules: Vec<Module>,
+    /// (from, input, amount) of each module's connections
+    connections: Vec<Vec<(usize, Input, f32)>>,
+    /// Last output of each module
+    values: Vec<f32>,
+    finished: Vec<bool>,
+    output: usize,
+    ends_with: Option<usize>,
+}
+
+impl GenSource for PatchGraph {
+    fn fill(&mut self, out: &mut [f32]) -> usize {
+        // Modules can feed back into each other, so they're run a sample at a time
+        for (idx, sample) in out.iter_mut().enumerate() {
+            let Some(next) = self.process() else {
+                return idx;
+            };
+            *sample = next;
+        }
+        out.len()
+    }
+
+    fn retrigger(&mut self) -> bool {
+        self.values.fill(0.);
+        self.finished.fill(false);
+        self.modules.iter_mut().all(Module::retrigger)
+    }
+}
+
+impl Default for PatchGraph {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PatchGraph {
+    pub fn new() -> Self {
+        PatchGraph {
+            modules: Vec::new(),
+            connections: Vec::new(),
+            values: Vec::new(),
+            finished: Vec::new(),
+            output: 0,
+            ends_with: None,
+        }
+    }
+
+    fn add(&mut self, module: Module) -> NodeId {
+        self.modules.push(module);
+        self.connections.push(Vec::new());
+        self.values.push(0.);
+        self.finished.push(false);
+        NodeId(self.modules.len() - 1)
+    }
+
+    /// Any source, eg: an `Envelope` or LFO. Outputs 0.0 once it ends.
+    pub fn add_source<T>(&mut self, source: T) -> NodeId
+    where
+        T: GenSource,
+    {
+        self.add(Module::Source(RawSource::new(source)))
+    }
+
+    /// Oscillator at `frequency` hz, takes `Input::Pitch`
+    pub fn add_oscillator<T>(&mut self, oscillator: T, frequency: f32) -> NodeId
+    where
+        T: Oscillator,
+    {
+        let pitch = Port::new();
+        let oscillator: Box<dyn Oscillator> = Box::new(oscillator);
+        let vco = Vco::new(oscillator, frequency, pitch.clone());
+        self.add(Module::Oscillator { vco, pitch })
+    }
+
+    /// `Svf` at `cutoff` hz, takes `Input::Audio`, `Input::Cutoff` and `Input::Resonance`
+    pub fn add_filter(&mut self, mode: FilterMode, cutoff: f32, resonance: f32) -> NodeId {
+        let audio = Port::new();
+        let cutoff = volts_per_frequency(cutoff);
+        let svf = Svf::new(audio.clone(), mode, frequency_per_volt(cutoff), resonance);
+        self.add(Module::Filter {
+            svf,
+            audio,
+            cutoff,
+            resonance,
+            last_cutoff: cutoff,
+        })
+    }
+
+    /// `Vca` at `gain`, takes `Input::Audio` and `Input::Gain`
+    pub fn add_vca(&mut self, gain: f32) -> NodeId {
+        let audio = Port::new();
+        let gain_port = Port::new();
+        let vca = Vca::new(audio.clone(), gain_port.clone());
+        self.add(Module::Vca {
+            vca,
+            audio,
+            gain_port,
+            gain,
+        })
+    }
+
+    /// The module added `index`th, for building from data
+    pub fn node(&self, index: usize) -> Option<NodeId> {
+        (index < self.modules.len()).then_some(NodeId(index))
+    }
+
+    /// Add `amount` times the output of `from` to an input of `to`
+    pub fn connect(&mut self, from: NodeId, to: NodeId, input: Input, amount: f32) {
+        self.connections[to.0].push((from.0, input, amount));
+    }
+
+    /// Module played by the graph, defaults to the first one
+    pub fn set_output(&mut self, node: NodeId) {
+        self.output = node.0;
+    }
+
+    /// End the graph when this module ends, rather than the output.
+    /// eg: An amplitude envelope routed to a VCA's gain.
+    pub fn ends_with(&mut self, node: NodeId) {
+        self.ends_with = Some(node.0);
+    }
+
+    pub fn into_raw(self) -> RawSource {
+        RawSource::new(self)
+    }
+
+    /// Run every module for a sample
+    fn process(&mut self) -> Option<f32> {
+        for idx in 0..self.modules.len() {
+            if self.finished[idx] {
+                continue;
+            }
+
+            let mut inputs = [0.0; INPUTS];
+            for &(from, input, amount) in &self.connections[idx] {
+                inputs[input as usize] += self.values[from] * amount;
+            }
+
+            match self.modules[idx].process(&inputs) {
+                Some(value) => self.values[idx] = value,
+                None => {
+                    self.values[idx] = 0.;
+                    self.finished[idx] = true;
+                }
+            }
+        }
+
+        let end = self.ends_with.unwrap_or(self.output);
+        if *self.finished.get(end)? {
+            return None;
+        }
+        Some(self.values[self.output])
+    }
+}
+
+impl Iterator for PatchGraph {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        next_sample(self)
+    }
+}
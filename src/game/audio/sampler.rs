@@ -0,0 +1,189 @@
+//! Playback of decoded audio files, eg: drum one-shots.
+
+use super::audio_generator::*;
+use bevy::asset::{AssetLoader, LoadContext, LoadedAsset};
+use bevy::reflect::TypeUuid;
+use bevy::utils::{BoxedFuture, HashMap};
+use rodio::decoder::{Decoder, DecoderError};
+use rodio::source::Source;
+use std::fmt;
+use std::io::{self, Cursor};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+/// A decoded audio file, mixed down to mono.
+/// Loaded as an asset from `.wav` and `.ogg` files.
+#[derive(Clone, TypeUuid)]
+#[uuid = "2A22B229-9493-423A-AB88-6D026A17898C"]
+pub struct Sample {
+    data: Arc<Vec<f32>>,
+    sample_rate: u32,
+}
+
+impl Sample {
+    pub fn decode(bytes: Vec<u8>) -> Result<Self, DecoderError> {
+        let decoder = Decoder::new(Cursor::new(bytes))?;
+        let channels = decoder.channels().max(1) as usize;
+        let sample_rate = decoder.sample_rate();
+        let interleaved: Vec<f32> = decoder.convert_samples().collect();
+        let data = interleaved
+            .chunks(channels)
+            .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+            .collect();
+        Ok(Sample {
+            data: Arc::new(data),
+            sample_rate,
+        })
+    }
+}
+
+/// Plays a `Sample`, pitch shifted by its frequency relative to `root`.
+/// Plays once, unless looped.
+pub struct Sampler {
+    data: Arc<Vec<f32>>,
+    /// Samples of `data` per output sample, at the root frequency
+    rate: f64,
+    /// Frequency the sample plays unshifted at
+    root: f32,
+    step: f64,
+    position: f64,
+    /// Start and end sample of the loop
+    loop_points: Option<(f64, f64)>,
+}
+
+impl GenSource for Sampler {
+    fn fill(&mut self, out: &mut [f32]) -> usize {
+        for (idx, sample) in out.iter_mut().enumerate() {
+            if let Some((start, end)) = self.loop_points {
+                if self.position >= end {
+                    self.position = start + (self.position - end) % (end - start);
+                }
+            }
+
+            // Linear interpolation between the two samples either side of the position
+            let index = self.position as usize;
+            let Some(&a) = self.data.get(index) else {
+                return idx;
+            };
+            let b = match self.loop_points {
+                Some((start, end)) if index + 1 >= end as usize => self.data[start as usize],
+                _ => self.data.get(index + 1).copied().unwrap_or(0.),
+            };
+            let fraction = self.position.fract() as f32;
+            self.position += self.step;
+            *sample = a + (b - a) * fraction;
+        }
+        out.len()
+    }
+
+    fn retrigger(&mut self) -> bool {
+        self.position = 0.0;
+        true
+    }
+}
+
+impl Sampler {
+    /// `root` is the frequency the sample was recorded at,
+    /// eg: `frequency_per_volt(0.2)` for a middle c.
+    pub fn new(sample: &Sample, root: f32) -> Self {
+        let rate = sample.sample_rate as f64 / sample_rate() as f64;
+        Sampler {
+            data: sample.data.clone(),
+            rate,
+            root,
+            step: rate,
+            position: 0.0,
+            loop_points: None,
+        }
+    }
+
+    /// Loop from sample `start` to `end` after reaching it, rather than ending.
+    /// Needs a `Vca` envelope to end.
+    pub fn looped(mut self, start: usize, end: usize) -> Self {
+        let end = end.min(self.data.len());
+        if start < end {
+            self.loop_points = Some((start as f64, end as f64));
+        }
+        self
+    }
+
+    pub fn into_raw(self) -> RawSource {
+        RawSource::new(self)
+    }
+}
+
+impl Oscillator for Sampler {
+    fn set_frequency(&mut self, frequency: f32) {
+        self.step = self.rate * (frequency / self.root) as f64;
+    }
+}
+
+impl Iterator for Sampler {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        next_sample(self)
+    }
+}
+
+/// Decoded samples by asset path, eg: "samples/kick.wav".
+/// Clones share the same samples, so ones loaded later are seen by all.
+#[derive(Clone, Default)]
+pub struct SampleLibrary {
+    samples: Arc<RwLock<HashMap<String, Sample>>>,
+}
+
+impl fmt::Debug for SampleLibrary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SampleLibrary").finish_non_exhaustive()
+    }
+}
+
+impl SampleLibrary {
+    pub fn get(&self, path: &str) -> Option<Sample> {
+        self.samples.read().ok()?.get(path).cloned()
+    }
+
+    /// Add a sample, replacing any at the same path
+    pub fn insert(&self, path: &str, sample: Sample) {
+        if let Ok(mut samples) = self.samples.write() {
+            samples.insert(path.to_string(), sample);
+        }
+    }
+
+    /// Decode every sample in `assets/dir` straight from disk, for when there's no `AssetServer`.
+    /// Files that fail to decode are skipped.
+    pub fn load_dir(&self, assets: &Path, dir: &str) -> io::Result<()> {
+        for entry in std::fs::read_dir(assets.join(dir))? {
+            let path = entry?.path();
+            let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            if let Ok(sample) = Sample::decode(std::fs::read(&path)?) {
+                self.insert(&format!("{}/{}", dir, name), sample);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct SampleLoader;
+
+impl AssetLoader for SampleLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let sample = Sample::decode(bytes.to_vec())?;
+            load_context.set_default_asset(LoadedAsset::new(sample));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["wav", "ogg"]
+    }
+}
@@ -0,0 +1,367 @@
+use super::audio_generator::*;
+use crate::game::instrument::Instrument;
+use crate::game::song::{self, Note, Notes, Song};
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Time in seconds before the first step of a song
+const START_DELAY: f32 = 1.0;
+
+/// Sent from the audio thread as the song plays.
+pub enum SongEvent {
+    /// A 16th note step started, with the note of each track that triggered on it.
+    Step { notes: [Option<i32>; 4] },
+    /// Played past the last chain.
+    Finished,
+}
+
+/// ECS side of a playing `Sequencer`.
+#[derive(Resource)]
+pub struct SequencerHandle {
+    next_chain: Arc<AtomicBool>,
+    stopped: Arc<AtomicBool>,
+    chain: Arc<AtomicUsize>,
+    new_song: Arc<Mutex<Option<Song>>>,
+    events: Mutex<Receiver<SongEvent>>,
+}
+
+impl SequencerHandle {
+    /// Move on to the next chain once the current one finishes.
+    pub fn next_chain(&self) {
+        self.next_chain.store(true, Ordering::Relaxed);
+    }
+
+    /// Swap in a new version of the song, from the next step on.
+    pub fn set_song(&self, song: Song) {
+        *self.new_song.lock().unwrap() = Some(song);
+    }
+
+    /// Chain of the step that played last
+    pub fn chain(&self) -> usize {
+        self.chain.load(Ordering::Relaxed)
+    }
+
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+
+    /// Events sent since the last call
+    pub fn events(&self) -> Vec<SongEvent> {
+        self.events.lock().unwrap().try_iter().collect()
+    }
+}
+
+/// Steps built ahead of the one playing.
+/// `next_chain` and new songs are picked up this far ahead of what's heard.
+const LOOKAHEAD_STEPS: usize = 2;
+
+/// Ended notes waiting to go back to their pool, more are dropped on the audio thread
+const RECYCLE_BACKLOG: usize = 64;
+
+/// Most sources kept ready to play each note
+const MAX_POOLED: usize = 4;
+
+/// Numbers every set of pools, so sources only go back to the pools they came from
+static POOLS: AtomicU32 = AtomicU32::new(0);
+
+/// Which pool a note's source goes back to once it ends, see `Sequencer::recycle`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PoolId {
+    pools: u32,
+    note: usize,
+}
+
+/// Sources of notes that have ended, retriggered to play the same note again
+/// rather than building it again.
+/// A pool for each note of each instrument, emptied when the song changes.
+struct VoicePool {
+    pools: u32,
+    /// Pool of each note played, by instrument, frequency, sustain and velocity
+    notes: HashMap<(usize, u32, u32, u32), usize>,
+    sources: Vec<Vec<RawSource>>,
+}
+
+impl VoicePool {
+    fn new() -> Self {
+        VoicePool {
+            pools: POOLS.fetch_add(1, Ordering::Relaxed),
+            notes: HashMap::default(),
+            sources: Vec::new(),
+        }
+    }
+
+    /// A retriggered source of the note if there's one, else a new one
+    fn play(
+        &mut self,
+        instrument: &Arc<Instrument>,
+        note: Note,
+        velocity: f32,
+    ) -> (RawSource, PoolId) {
+        // The song holds its instruments until the pools are emptied, so no other is at the same address
+        let key = (
+            Arc::as_ptr(instrument) as usize,
+            note.frequency.to_bits(),
+            note.sustain.to_bits(),
+            velocity.to_bits(),
+        );
+        let next = self.sources.len();
+        let idx = *self.notes.entry(key).or_insert(next);
+        if idx == next {
+            self.sources.push(Vec::new());
+        }
+
+        let source = self.sources[idx]
+            .pop()
+            .unwrap_or_else(|| song::play_note(instrument, note, velocity));
+        let id = PoolId {
+            pools: self.pools,
+            note: idx,
+        };
+        (source, id)
+    }
+
+    /// Keep an ended source to play its note again, if it can be retriggered
+    fn recycle(&mut self, id: PoolId, mut source: RawSource) {
+        if id.pools != self.pools {
+            return;
+        }
+        if let Some(pool) = self.sources.get_mut(id.note) {
+            if pool.len() < MAX_POOLED && source.retrigger() {
+                pool.push(source);
+            }
+        }
+    }
+}
+
+/// A step of the song, with its notes built ready to play
+struct Step {
+    notes: Notes<(RawSource, PoolId)>,
+    chain: usize,
+    /// Seconds until the next step
+    step_time: f32,
+    /// Seconds it's played after the beat grid
+    swing: f32,
+    /// The song ends after this step
+    last: bool,
+}
+
+/// Builds the steps of a `Sequencer` on its own thread, so the audio thread never
+/// allocates notes or drops old songs.
+/// Notes that have ended come back to be played again.
+struct StepBuilder {
+    song: Song,
+    pool: VoicePool,
+    idx: usize,
+    chain: usize,
+    /// Play each chain once, rather than waiting on `next_chain`
+    play_through: bool,
+    next_chain: Arc<AtomicBool>,
+    new_song: Arc<Mutex<Option<Song>>>,
+}
+
+impl StepBuilder {
+    /// Build steps until the song ends, or the sequencer is dropped.
+    /// Notes that have `ended` are pooled to be played again.
+    fn run(mut self, steps: SyncSender<Step>, ended: Receiver<(PoolId, RawSource)>) {
+        loop {
+            for (id, source) in ended.try_iter() {
+                self.pool.recycle(id, source);
+            }
+            let step = self.build();
+            let last = step.last;
+            if steps.send(step).is_err() || last {
+                return;
+            }
+        }
+    }
+
+    fn build(&mut self) -> Step {
+        if let Some(song) = self.new_song.lock().unwrap().take() {
+            // The old song is dropped here, rather than on the audio thread
+            self.song = song;
+            self.pool = VoicePool::new();
+            if self.idx >= self.song.len(self.chain) {
+                self.idx = 0;
+            }
+        }
+
+        let pool = &mut self.pool;
+        let mut step = Step {
+            notes: self
+                .song
+                .note(self.idx, self.chain, |instrument, note, velocity| {
+                    pool.play(instrument, note, velocity)
+                }),
+            chain: self.chain,
+            step_time: self.song.step_time(self.chain),
+            swing: self.song.swing_delay(self.idx, self.chain),
+            last: false,
+        };
+
+        self.idx += 1;
+        if self.idx >= self.song.len(self.chain) {
+            self.idx = 0;
+            if self.play_through || self.next_chain.swap(false, Ordering::Relaxed) {
+                self.chain += 1;
+                step.last = self.chain >= self.song.max_chains();
+            }
+        }
+        step
+    }
+}
+
+/// Plays a `Song`, counting samples so every step starts exactly on its 16th.
+/// Follows the song's tempo and swing.
+/// Runs on the audio thread, ticked once per sample by the mixer.
+/// Notes are built ahead of time by a `StepBuilder`.
+pub struct Sequencer {
+    steps: Receiver<Step>,
+    recycled: SyncSender<(PoolId, RawSource)>,
+    /// Waiting for its time to play
+    next: Option<Step>,
+    /// Wait for steps to be built, rather than playing them late, for offline rendering
+    blocking: bool,
+    /// Samples ticked so far
+    sample: u64,
+    /// Sample the next step falls on, before swing.
+    /// Kept fractional so timing doesn't drift.
+    grid: f64,
+    finished: bool,
+
+    stopped: Arc<AtomicBool>,
+    chain: Arc<AtomicUsize>,
+    events: Option<Sender<SongEvent>>,
+}
+
+impl Sequencer {
+    pub fn new(song: Song) -> (Self, SequencerHandle) {
+        let (sender, receiver) = channel();
+        let next_chain = Arc::new(AtomicBool::new(false));
+        let new_song = Arc::new(Mutex::new(None));
+        let mut sequencer = Self::spawn(StepBuilder {
+            song,
+            pool: VoicePool::new(),
+            idx: 0,
+            chain: 0,
+            play_through: false,
+            next_chain: next_chain.clone(),
+            new_song: new_song.clone(),
+        });
+        sequencer.grid = (START_DELAY * sample_rate()) as f64;
+        sequencer.events = Some(sender);
+
+        let handle = SequencerHandle {
+            next_chain,
+            stopped: sequencer.stopped.clone(),
+            chain: sequencer.chain.clone(),
+            new_song,
+            events: Mutex::new(receiver),
+        };
+        (sequencer, handle)
+    }
+
+    /// Play every chain once, straight away, for offline rendering.
+    pub fn play_through(song: Song) -> Self {
+        let mut sequencer = Self::spawn(StepBuilder {
+            song,
+            pool: VoicePool::new(),
+            idx: 0,
+            chain: 0,
+            play_through: true,
+            next_chain: Arc::default(),
+            new_song: Arc::default(),
+        });
+        sequencer.blocking = true;
+        sequencer
+    }
+
+    fn spawn(builder: StepBuilder) -> Self {
+        let (steps, receiver) = sync_channel(LOOKAHEAD_STEPS);
+        let (recycled, ended) = sync_channel(RECYCLE_BACKLOG);
+        thread::spawn(move || builder.run(steps, ended));
+        Sequencer {
+            steps: receiver,
+            recycled,
+            next: None,
+            blocking: false,
+            sample: 0,
+            grid: 0.,
+            finished: false,
+            stopped: Arc::new(AtomicBool::new(false)),
+            chain: Arc::default(),
+            events: None,
+        }
+    }
+
+    /// `true` once the song has ended, or the handle stopped it.
+    pub fn finished(&self) -> bool {
+        self.finished || self.stopped.load(Ordering::Relaxed)
+    }
+
+    /// Hand back the source of a note that's ended, to be retriggered the next time it plays.
+    /// Dropped if the builder has fallen behind or the song has ended.
+    pub fn recycle(&self, pool: PoolId, source: RawSource) {
+        let _ = self.recycled.try_send((pool, source));
+    }
+
+    /// Advance one sample.
+    /// `play` is called with the source, track and pool of each note starting on this sample.
+    pub fn tick<F>(&mut self, mut play: F)
+    where
+        F: FnMut(RawSource, usize, PoolId),
+    {
+        if self.finished() {
+            return;
+        }
+
+        if self.next.is_none() {
+            self.next = if self.blocking {
+                self.steps.recv().ok()
+            } else {
+                // Played late if it isn't ready, rather than waiting on the builder
+                self.steps.try_recv().ok()
+            };
+        }
+        if let Some(step) = self.next.take() {
+            let swing = step.swing as f64 * sample_rate() as f64;
+            if self.sample as f64 >= self.grid + swing {
+                self.trigger(step, &mut play);
+            } else {
+                self.next = Some(step);
+            }
+        }
+        self.sample += 1;
+    }
+
+    fn trigger<F>(&mut self, step: Step, play: &mut F)
+    where
+        F: FnMut(RawSource, usize, PoolId),
+    {
+        let mut notes = [None; 4];
+        for (track, maybe_note) in step.notes.into_iter().enumerate() {
+            if let Some((note, (source, pool))) = maybe_note {
+                notes[track] = Some(note);
+                play(source, track, pool);
+            }
+        }
+        self.chain.store(step.chain, Ordering::Relaxed);
+        self.send(SongEvent::Step { notes });
+
+        self.grid += step.step_time as f64 * sample_rate() as f64;
+        if step.last {
+            self.finished = true;
+            self.send(SongEvent::Finished);
+        }
+    }
+
+    fn send(&self, event: SongEvent) {
+        if let Some(events) = &self.events {
+            // Only fails once the handle is dropped, then nobody is listening.
+            let _ = events.send(event);
+        }
+    }
+}
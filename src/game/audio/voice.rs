@@ -0,0 +1,293 @@
+use super::audio_generator::*;
+use super::mixer::Bus;
+use super::sequencer::PoolId;
+use rodio::source::Source;
+
+/// Time in seconds a stolen voice takes to fade out, to avoid clicks.
+const STEAL_FADE: f32 = 0.005;
+/// Per sample decay of a voice's level meter.
+const LEVEL_DECAY: f32 = 0.999;
+
+/// What a voice is playing, each category has its own voice limit.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VoiceCategory {
+    /// Notes from the song
+    Music,
+    /// Sound effects, eg bullet hits
+    Sfx,
+}
+
+/// Which voice to stop when a new one needs room.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum StealMode {
+    Oldest,
+    Quietest,
+}
+
+/// How many voices can play at once, and which to stop for a new one past that.
+#[derive(Clone, Copy, Debug)]
+pub struct VoiceLimits {
+    /// Voices across all categories
+    pub max_voices: usize,
+    pub max_music_voices: usize,
+    pub max_sfx_voices: usize,
+    pub steal_mode: StealMode,
+}
+
+impl Default for VoiceLimits {
+    fn default() -> Self {
+        VoiceLimits {
+            max_voices: 16,
+            max_music_voices: 12,
+            max_sfx_voices: 8,
+            steal_mode: StealMode::Oldest,
+        }
+    }
+}
+
+/// A slot the `VoiceManager` plays sources in, reused once its source ends.
+/// Tracks its level while playing, and fades out once stolen.
+struct Voice {
+    /// `None` while the slot is free
+    source: Option<RawSource>,
+    /// Where the source goes back to once it ends, to be played again
+    pool: Option<PoolId>,
+    bus: Bus,
+    /// Order the voice was started in
+    started: u64,
+    level: f32,
+    /// `level` is only known once the voice has been rendered
+    rendered: bool,
+    /// Gain while fading out after being stolen
+    fade: Option<f32>,
+}
+
+impl Voice {
+    /// Fades don't count towards the voice limits
+    fn counted(&self) -> bool {
+        self.source.is_some() && self.fade.is_none()
+    }
+
+    /// Render into `out` like `GenSource::fill`
+    fn fill(&mut self, out: &mut [f32]) -> usize {
+        let Some(source) = &mut self.source else {
+            return 0;
+        };
+        let mut len = source.fill(out);
+
+        if let Some(fade) = &mut self.fade {
+            let step = 1.0 / (STEAL_FADE * sample_rate() * source.channels() as f32);
+            for (idx, sample) in out[..len].iter_mut().enumerate() {
+                *fade -= step;
+                if *fade <= 0. {
+                    len = idx;
+                    break;
+                }
+                *sample *= *fade;
+            }
+        }
+
+        for sample in &out[..len] {
+            self.level = sample.abs().max(self.level * LEVEL_DECAY);
+        }
+        self.rendered = true;
+        len
+    }
+}
+
+/// Plays sources in a set of reused voices, stealing voices when over a limit.
+/// Pooled sources are handed back once they end, see `ended`.
+pub struct VoiceManager {
+    /// Applied from the next voice started
+    pub limits: VoiceLimits,
+
+    /// Reused once free, only more than `limits.max_voices` while stolen voices fade out
+    voices: Vec<Voice>,
+    /// Slots playing, in the order they were started
+    playing: Vec<usize>,
+    free: Vec<usize>,
+    started: u64,
+    /// Samples of the voice being mixed
+    buffer: Vec<f32>,
+    /// Pooled sources that have ended, until they're handed back
+    ended: Vec<(PoolId, RawSource)>,
+}
+
+impl Default for VoiceManager {
+    fn default() -> Self {
+        VoiceManager::new(VoiceLimits::default())
+    }
+}
+
+impl VoiceManager {
+    pub fn new(limits: VoiceLimits) -> Self {
+        VoiceManager {
+            limits,
+            voices: Vec::new(),
+            playing: Vec::with_capacity(limits.max_voices),
+            free: Vec::new(),
+            started: 0,
+            buffer: Vec::new(),
+            ended: Vec::with_capacity(limits.max_voices),
+        }
+    }
+
+    fn limit(&self, category: VoiceCategory) -> usize {
+        match category {
+            VoiceCategory::Music => self.limits.max_music_voices,
+            VoiceCategory::Sfx => self.limits.max_sfx_voices,
+        }
+    }
+
+    /// Play `source` on `bus`, stealing an old voice if over a limit.
+    /// Handed back by `ended` with its `pool` once it ends.
+    pub fn start(&mut self, source: RawSource, bus: Bus, pool: Option<PoolId>) {
+        let category = bus.category();
+        let (mut in_category, mut total) = (0, 0);
+        for voice in self.playing.iter().map(|&slot| &self.voices[slot]) {
+            if voice.counted() {
+                total += 1;
+                if voice.bus.category() == category {
+                    in_category += 1;
+                }
+            }
+        }
+        if in_category >= self.limit(category) {
+            self.steal(Some(category));
+        } else if total >= self.limits.max_voices {
+            self.steal(None);
+        }
+
+        self.started += 1;
+        let voice = Voice {
+            source: Some(source),
+            pool,
+            bus,
+            started: self.started,
+            level: 0.,
+            rendered: false,
+            fade: None,
+        };
+        let slot = match self.free.pop() {
+            Some(slot) => {
+                self.voices[slot] = voice;
+                slot
+            }
+            None => {
+                self.voices.push(voice);
+                self.voices.len() - 1
+            }
+        };
+        self.playing.push(slot);
+    }
+
+    /// Fade out a voice, from `category` if given, else from any category.
+    fn steal(&mut self, category: Option<VoiceCategory>) {
+        let candidates = self
+            .playing
+            .iter()
+            .map(|&slot| &self.voices[slot])
+            .enumerate()
+            .filter(|(_, voice)| voice.counted())
+            .filter(|(_, voice)| category.is_none() || category == Some(voice.bus.category()));
+
+        let stolen = match self.limits.steal_mode {
+            StealMode::Oldest => candidates
+                .min_by_key(|(_, voice)| voice.started)
+                .map(|(idx, _)| idx),
+            // Voices started this block haven't been heard yet, so they're only stolen
+            // once every rendered voice is gone, oldest first
+            StealMode::Quietest => candidates
+                .min_by(|(_, a), (_, b)| {
+                    b.rendered
+                        .cmp(&a.rendered)
+                        .then(a.level.total_cmp(&b.level))
+                })
+                .map(|(idx, _)| idx),
+        };
+
+        if let Some(idx) = stolen {
+            self.voices[self.playing[idx]].fade = Some(1.0);
+        }
+    }
+
+    /// Render the next `frames` of every voice, in the order they were started.
+    /// `mix` is called with each voice's bus, channels and samples, which end early if it ended.
+    pub fn render<F>(&mut self, frames: usize, mut mix: F)
+    where
+        F: FnMut(Bus, usize, &[f32]),
+    {
+        if self.buffer.len() < frames * 2 {
+            self.buffer.resize(frames * 2, 0.);
+        }
+        let (voices, free, buffer, ended) = (
+            &mut self.voices,
+            &mut self.free,
+            &mut self.buffer,
+            &mut self.ended,
+        );
+        self.playing.retain(|&slot| {
+            let voice = &mut voices[slot];
+            let Some(channels) = voice
+                .source
+                .as_ref()
+                .map(|source| source.channels() as usize)
+            else {
+                free.push(slot);
+                return false;
+            };
+
+            let len = voice.fill(&mut buffer[..frames * channels]) / channels;
+            mix(voice.bus, channels, &buffer[..len * channels]);
+            if len < frames {
+                if let (Some(source), Some(pool)) = (voice.source.take(), voice.pool) {
+                    ended.push((pool, source));
+                }
+                free.push(slot);
+                return false;
+            }
+            true
+        });
+    }
+
+    /// Pooled sources that ended since the last call, with their pools
+    pub fn ended(&mut self) -> impl Iterator<Item = (PoolId, RawSource)> + '_ {
+        self.ended.drain(..)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Levels of the voices that play through the next `frames`
+    fn playing(voices: &mut VoiceManager, frames: usize) -> Vec<f32> {
+        let mut levels = Vec::new();
+        voices.render(frames, |_, _, samples| {
+            if samples.len() == frames {
+                levels.push(samples[frames - 1]);
+            }
+        });
+        levels
+    }
+
+    #[test]
+    fn quietest_keeps_notes_started_together() {
+        let mut voices = VoiceManager::new(VoiceLimits {
+            max_music_voices: 2,
+            steal_mode: StealMode::Quietest,
+            ..VoiceLimits::default()
+        });
+        let note = |amplitude| Envelope::new(amplitude, 0.0, 10.0, 0.0).into_raw();
+        voices.start(note(0.5), Bus::Track(0), None);
+        voices.start(note(0.5), Bus::Track(0), None);
+        playing(&mut voices, 64);
+
+        // A chord on one frame, at the limit
+        voices.start(note(0.25), Bus::Track(0), None);
+        voices.start(note(0.125), Bus::Track(0), None);
+        // Once the stolen voices have faded out
+        playing(&mut voices, (STEAL_FADE * sample_rate()) as usize + 1);
+        assert_eq!(playing(&mut voices, 64), [0.25, 0.125]);
+    }
+}
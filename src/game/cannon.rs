@@ -1,4 +1,5 @@
 use super::assets::Sprites;
+use super::audio::analysis::Analysis;
 use super::world::WorldPosition;
 use super::GameState;
 use bevy::prelude::*;
@@ -7,7 +8,9 @@ pub struct CannonPlugin;
 
 impl bevy::app::Plugin for CannonPlugin {
     fn build(&self, app: &mut App) {
-        app.add_system(cannon_move_system.in_set(OnUpdate(GameState::Playing)));
+        app.add_systems(
+            (cannon_move_system, cannon_glow_system).in_set(OnUpdate(GameState::Playing)),
+        );
     }
 }
 
@@ -46,6 +49,11 @@ impl Cannon {
         }
     }
 
+    /// Stereo position of this cannon's track, from the side of the arena it fires from
+    pub fn pan(&self) -> f32 {
+        -self.heading.normalize_or_zero().x * 0.7
+    }
+
     fn horizontal(&self) -> bool {
         self.heading.y.abs() > self.heading.x.abs()
     }
@@ -134,3 +142,19 @@ fn cannon_move_system(mut query: Query<(&mut Cannon, &mut WorldPosition)>) {
         }
     }
 }
+
+/// Cannons glow yellow as their track plays
+fn cannon_glow_system(
+    analysis: Res<Analysis>,
+    cannon_query: Query<(&Cannon, &Children)>,
+    mut sprite_query: Query<&mut TextureAtlasSprite>,
+) {
+    for (cannon, children) in &cannon_query {
+        let glow = (analysis.track(cannon.track).rms * 4.).min(1.) * 0.6;
+        for child in children {
+            if let Ok(mut sprite) = sprite_query.get_mut(*child) {
+                sprite.color = Color::rgb(1., 1., 1. - glow);
+            }
+        }
+    }
+}
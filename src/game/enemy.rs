@@ -1,8 +1,9 @@
 use super::animation::{Animated, Animation, AnimationFrame, AnimationMarker};
 use super::audio::audio_generator::*;
+use super::audio::effects::{Bitcrusher, Fx};
 use super::audio::Audio;
 use super::player::Player;
-use super::world::{Bullet, BulletType, Wall, WorldPosition};
+use super::world::{Bullet, BulletType, Wall, World, WorldPosition};
 use super::GameState;
 use bevy::prelude::*;
 use bevy::sprite::collide_aabb::{collide, Collision};
@@ -180,6 +181,7 @@ fn enemy_bullet_system(
     bullet_query: Query<(Entity, &WorldPosition, &Bullet), Without<Player>>,
     mut event_writer: EventWriter<EnemyKilledEvent>,
     audio: Res<Audio>,
+    world_query: Query<&World>,
 ) {
     let bullet_size = Vec2::new(4., 4.);
     let enemy_size = Vec2::new(16., 12.);
@@ -209,7 +211,11 @@ fn enemy_bullet_system(
 
                 let vco = Vco::new(RampWave::new(440.), 440., RampWave::new(20.));
                 let vca = Vca::new(vco, Envelope::new(0.1, 0.1, 0.0, 0.1));
-                audio.play(vca.as_raw());
+                let pan = world_query
+                    .get_single()
+                    .map_or(0., |world| world.pan(enemy_position.position));
+                let crushed = Fx::new(vca, Bitcrusher::new(6, 2.));
+                audio.play(Pan::new(crushed, pan).into_raw());
             }
         }
         if enemy.health <= 0 {
@@ -0,0 +1,1229 @@
+//! Instruments described as data, so songs can share and tweak them.
+
+use super::audio::audio_generator::*;
+use super::audio::effects::{Bitcrusher, Chorus, Delay, Fx, Reverb, Shape, Waveshaper, MAX_DELAY};
+use super::audio::patch_graph::{Input, PatchGraph};
+use super::audio::sampler::{SampleLibrary, Sampler};
+use super::song::Note;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use serde::Deserialize;
+use std::sync::{Arc, RwLock};
+
+/// Control voltage, added to a pitch or cutoff
+#[derive(Clone, Debug, Deserialize)]
+pub enum Cv {
+    /// See `Envelope`
+    Envelope {
+        amplitude: f32,
+        attack: f32,
+        hold: f32,
+        release: f32,
+    },
+    /// `depth` volts of `waveform` at `frequency` hz
+    Lfo {
+        waveform: Waveform,
+        frequency: f32,
+        depth: f32,
+    },
+}
+
+impl Cv {
+    fn source(&self) -> RawSource {
+        match *self {
+            Cv::Envelope {
+                amplitude,
+                attack,
+                hold,
+                release,
+            } => Envelope::new(amplitude, attack, hold, release).into_raw(),
+            Cv::Lfo {
+                waveform,
+                frequency,
+                depth,
+            } => Attenuator::new(waveform.oscillator(frequency, false), depth).into_raw(),
+        }
+    }
+}
+
+/// A `Vcf`, or an `Svf` when given a mode
+#[derive(Clone, Debug, Deserialize)]
+pub struct Filter {
+    /// Cutoff as a multiple of the note frequency
+    pub cutoff: f32,
+    /// See `Vcf` or `Svf`, their ranges differ
+    pub resonance: f32,
+    /// Sweeps the cutoff
+    #[serde(default)]
+    pub cv: Option<Cv>,
+    #[serde(default)]
+    pub mode: Option<FilterMode>,
+    /// Added to the resonance, `Svf` only
+    #[serde(default)]
+    pub resonance_cv: Option<Cv>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct AmpEnvelope {
+    pub amplitude: f32,
+    pub attack: f32,
+    pub hold: f32,
+    pub release: f32,
+    /// Held on through ties, rather than always lasting the same time
+    #[serde(default)]
+    pub held: bool,
+}
+
+impl AmpEnvelope {
+    fn source(&self, note: Note) -> RawSource {
+        if self.held {
+            // Gate stays open through the attack, hold and any ties
+            let gate = Gate::timed(self.attack + self.hold + note.sustain);
+            Adsr::new(self.amplitude, self.attack, 0.0, 1.0, self.release, gate).into_raw()
+        } else {
+            Envelope::new(self.amplitude, self.attack, self.hold, self.release).into_raw()
+        }
+    }
+}
+
+/// An effect a patch is played through
+#[derive(Clone, Debug, Deserialize)]
+pub enum EffectDef {
+    Waveshaper {
+        shape: Shape,
+        drive: f32,
+        level: f32,
+    },
+    Bitcrusher {
+        bits: u32,
+        downsample: f32,
+    },
+    Delay {
+        seconds: f32,
+        feedback: f32,
+        mix: f32,
+    },
+    Chorus {
+        rate: f32,
+        depth: f32,
+        mix: f32,
+    },
+    Reverb {
+        room_size: f32,
+        damping: f32,
+        mix: f32,
+    },
+}
+
+impl EffectDef {
+    fn apply(&self, source: RawSource) -> RawSource {
+        match *self {
+            EffectDef::Waveshaper {
+                shape,
+                drive,
+                level,
+            } => Fx::new(source, Waveshaper::new(shape, drive, level)).into_raw(),
+            EffectDef::Bitcrusher { bits, downsample } => {
+                Fx::new(source, Bitcrusher::new(bits, downsample)).into_raw()
+            }
+            EffectDef::Delay {
+                seconds,
+                feedback,
+                mix,
+            } => Fx::new(source, Delay::new(seconds, feedback, mix)).into_raw(),
+            EffectDef::Chorus { rate, depth, mix } => {
+                Fx::new(source, Chorus::new(rate, depth, mix)).into_raw()
+            }
+            EffectDef::Reverb {
+                room_size,
+                damping,
+                mix,
+            } => Fx::new(source, Reverb::new(room_size, damping, mix)).into_raw(),
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        match *self {
+            EffectDef::Delay { seconds, .. } if !(seconds > 0. && seconds <= MAX_DELAY) => {
+                Err(format!(
+                    "delay of {} seconds, needs to be over 0 and up to {}",
+                    seconds, MAX_DELAY
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Oscillator, then pitch CV, filter, gain, amplitude envelope and effects.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Patch {
+    pub waveform: Waveform,
+    #[serde(default)]
+    pub band_limited: bool,
+    /// Play at this frequency in hz whatever the note, eg: for noise
+    #[serde(default)]
+    pub fixed_frequency: Option<f32>,
+    #[serde(default)]
+    pub pitch_cv: Option<Cv>,
+    /// Pulse width, `Square` only
+    #[serde(default)]
+    pub duty: Option<Duty>,
+    /// Added to the pulse width, eg: an LFO for PWM. `Square` only
+    #[serde(default)]
+    pub pwm: Option<Cv>,
+    #[serde(default)]
+    pub filter: Option<Filter>,
+    #[serde(default = "default_gain")]
+    pub gain: f32,
+    pub amp: AmpEnvelope,
+    /// How hard `Pluck`, `Bar` and `Glass` are plucked or struck, 0 to only play the excitation
+    #[serde(default = "default_gain")]
+    pub strike: f32,
+    /// White noise fed into `Pluck`, `Bar` and `Glass` as they play, eg: to bow a string
+    #[serde(default)]
+    pub excitation: Option<AmpEnvelope>,
+    /// In order, after the amplitude envelope
+    #[serde(default)]
+    pub effects: Vec<EffectDef>,
+}
+
+fn default_gain() -> f32 {
+    1.0
+}
+
+impl Patch {
+    pub fn new(waveform: Waveform) -> Self {
+        Patch {
+            waveform,
+            band_limited: false,
+            fixed_frequency: None,
+            pitch_cv: None,
+            duty: None,
+            pwm: None,
+            filter: None,
+            gain: 1.0,
+            amp: AmpEnvelope {
+                amplitude: 0.3,
+                attack: 0.05,
+                hold: 0.05,
+                release: 0.2,
+                held: false,
+            },
+            strike: 1.0,
+            excitation: None,
+            effects: Vec::new(),
+        }
+    }
+
+    fn play(&self, note: Note) -> RawSource {
+        let frequency = self.fixed_frequency.unwrap_or(note.frequency);
+        let oscillator = match self.waveform {
+            Waveform::Square if self.duty.is_some() || self.pwm.is_some() => {
+                let duty = self.duty.unwrap_or_default().fraction();
+                let mut square = SquareWave::new(frequency).with_duty(duty);
+                if self.band_limited {
+                    square = square.band_limited();
+                }
+                if let Some(cv) = &self.pwm {
+                    square = square.with_duty_cv(cv.source());
+                }
+                Box::new(square)
+            }
+            Waveform::Pluck | Waveform::Bar | Waveform::Glass => {
+                let excitation = self.excitation.as_ref().map(|amp| {
+                    Vca::new(Noise::new(NoiseColour::White), amp.source(note)).into_raw()
+                });
+                self.waveform.excited(frequency, self.strike, excitation)
+            }
+            waveform => waveform.oscillator(frequency, self.band_limited),
+        };
+        let mut source = match &self.pitch_cv {
+            Some(cv) => Vco::new(oscillator, frequency, cv.source()).into_raw(),
+            None => RawSource::new(oscillator),
+        };
+
+        if let Some(filter) = &self.filter {
+            let cutoff = frequency * filter.cutoff;
+            source = if let Some(mode) = filter.mode {
+                let mut svf = Svf::new(source, mode, cutoff, filter.resonance);
+                if let Some(cv) = &filter.cv {
+                    svf = svf.with_cutoff_cv(cv.source());
+                }
+                if let Some(cv) = &filter.resonance_cv {
+                    svf = svf.with_resonance_cv(cv.source());
+                }
+                svf.into_raw()
+            } else {
+                let vcf = Vcf::new(source, cutoff, filter.resonance);
+                match &filter.cv {
+                    Some(cv) => Vco::new(vcf, cutoff, cv.source()).into_raw(),
+                    None => vcf.into_raw(),
+                }
+            };
+        }
+
+        if self.gain != 1.0 {
+            source = Attenuator::new(source, self.gain).into_raw();
+        }
+
+        let mut source = Vca::new(source, self.amp.source(note)).into_raw();
+        for effect in &self.effects {
+            source = effect.apply(source);
+        }
+        source
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        self.effects.iter().try_for_each(EffectDef::validate)
+    }
+
+    fn with_overrides(&self, overrides: &Overrides) -> Patch {
+        let mut patch = self.clone();
+        if let Some(waveform) = overrides.waveform {
+            patch.waveform = waveform;
+        }
+        if let Some(band_limited) = overrides.band_limited {
+            patch.band_limited = band_limited;
+        }
+        if let Some(duty) = overrides.duty {
+            patch.duty = Some(duty);
+        }
+        if let Some(cutoff) = overrides.cutoff {
+            let filter = patch.filter.get_or_insert(Filter {
+                cutoff,
+                resonance: 1.0,
+                cv: None,
+                mode: None,
+                resonance_cv: None,
+            });
+            filter.cutoff = cutoff;
+        }
+        if let (Some(resonance), Some(filter)) = (overrides.resonance, &mut patch.filter) {
+            filter.resonance = resonance;
+        }
+        if let Some(gain) = overrides.gain {
+            patch.gain = gain;
+        }
+        let amp = &mut patch.amp;
+        amp.amplitude = overrides.amplitude.unwrap_or(amp.amplitude);
+        amp.attack = overrides.attack.unwrap_or(amp.attack);
+        amp.hold = overrides.hold.unwrap_or(amp.hold);
+        amp.release = overrides.release.unwrap_or(amp.release);
+        patch
+    }
+}
+
+/// An operator of an `FmPatch`, see `Operator`
+#[derive(Clone, Debug, Deserialize)]
+pub struct OperatorDef {
+    /// Frequency as a multiple of the note frequency
+    pub ratio: f32,
+    /// Modulation depth in radians, or level if it's a carrier
+    pub index: f32,
+    #[serde(default)]
+    pub feedback: f32,
+    pub envelope: AmpEnvelope,
+}
+
+/// `FmVoice`, then pitch CV, gain and effects.
+/// The carriers' envelopes shape the amplitude.
+#[derive(Clone, Debug, Deserialize)]
+pub struct FmPatch {
+    #[serde(default)]
+    pub algorithm: FmAlgorithm,
+    /// 2 to 4, in the order the algorithm uses them
+    pub operators: Vec<OperatorDef>,
+    #[serde(default)]
+    pub pitch_cv: Option<Cv>,
+    #[serde(default = "default_gain")]
+    pub gain: f32,
+    #[serde(default)]
+    pub effects: Vec<EffectDef>,
+}
+
+impl FmPatch {
+    fn play(&self, note: Note) -> RawSource {
+        let operators = self
+            .operators
+            .iter()
+            .map(|op| {
+                Operator::new(op.ratio, op.index, op.envelope.source(note))
+                    .with_feedback(op.feedback)
+            })
+            .collect();
+        let voice = FmVoice::new(note.frequency, self.algorithm, operators);
+        let mut source = match &self.pitch_cv {
+            Some(cv) => Vco::new(voice, note.frequency, cv.source()).into_raw(),
+            None => voice.into_raw(),
+        };
+
+        if self.gain != 1.0 {
+            source = Attenuator::new(source, self.gain).into_raw();
+        }
+        for effect in &self.effects {
+            source = effect.apply(source);
+        }
+        source
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let count = self.operators.len();
+        if !(2..=MAX_OPERATORS).contains(&count) {
+            return Err(format!(
+                "FM needs 2 to {} operators, not {}",
+                MAX_OPERATORS, count
+            ));
+        }
+        self.effects.iter().try_for_each(EffectDef::validate)
+    }
+
+    fn with_overrides(&self, overrides: &Overrides) -> FmPatch {
+        let mut patch = self.clone();
+        if let Some(gain) = overrides.gain {
+            patch.gain = gain;
+        }
+        patch
+    }
+}
+
+/// Plays a sample file from `assets/`, then amplitude envelope and effects.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SamplerPatch {
+    /// Asset path, eg: "samples/kick.wav"
+    pub sample: String,
+    /// Frequency in hz the sample was recorded at, to follow the note pitch.
+    /// Plays unshifted whatever the note without one, eg: for drums.
+    #[serde(default)]
+    pub root: Option<f32>,
+    /// Start and end sample to loop between.
+    /// Needs an amp envelope to end the note, song files are rejected without one.
+    #[serde(default)]
+    pub loop_points: Option<(usize, usize)>,
+    #[serde(default = "default_gain")]
+    pub gain: f32,
+    /// One-shots play to their end without one
+    #[serde(default)]
+    pub amp: Option<AmpEnvelope>,
+    #[serde(default)]
+    pub effects: Vec<EffectDef>,
+    /// Where the sample is looked up, set by `Instrument::bind_samples`
+    #[serde(skip)]
+    library: SampleLibrary,
+}
+
+impl SamplerPatch {
+    pub fn new(sample: &str) -> Self {
+        SamplerPatch {
+            sample: sample.to_string(),
+            root: None,
+            loop_points: None,
+            gain: 1.0,
+            amp: None,
+            effects: Vec::new(),
+            library: SampleLibrary::default(),
+        }
+    }
+
+    fn play(&self, note: Note) -> RawSource {
+        // Silent until the sample has loaded, built again rather than retriggered till then
+        let Some(sample) = self.library.get(&self.sample) else {
+            return Envelope::new(0.0, 0.0, 0.0, 0.0).into_raw().once();
+        };
+
+        let root = self.root.unwrap_or(note.frequency);
+        let mut sampler = Sampler::new(&sample, root);
+        sampler.set_frequency(note.frequency);
+        if let Some((start, end)) = self.loop_points {
+            sampler = sampler.looped(start, end);
+        }
+
+        let mut source = match &self.amp {
+            Some(amp) => Vca::new(sampler, amp.source(note)).into_raw(),
+            None => sampler.into_raw(),
+        };
+        if self.gain != 1.0 {
+            source = Attenuator::new(source, self.gain).into_raw();
+        }
+        for effect in &self.effects {
+            source = effect.apply(source);
+        }
+        source
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.loop_points.is_some() && self.amp.is_none() {
+            return Err("loop_points needs an amp envelope to end the note".to_string());
+        }
+        self.effects.iter().try_for_each(EffectDef::validate)
+    }
+
+    fn with_overrides(&self, overrides: &Overrides) -> SamplerPatch {
+        let mut patch = self.clone();
+        if let Some(gain) = overrides.gain {
+            patch.gain = gain;
+        }
+        if let Some(amp) = &mut patch.amp {
+            amp.amplitude = overrides.amplitude.unwrap_or(amp.amplitude);
+            amp.attack = overrides.attack.unwrap_or(amp.attack);
+            amp.hold = overrides.hold.unwrap_or(amp.hold);
+            amp.release = overrides.release.unwrap_or(amp.release);
+        }
+        patch
+    }
+}
+
+/// A module of a `GraphPatch`
+#[derive(Clone, Debug, Deserialize)]
+pub enum ModuleDef {
+    /// At `ratio` times the note frequency, or `fixed_frequency` hz
+    Oscillator {
+        waveform: Waveform,
+        #[serde(default)]
+        band_limited: bool,
+        #[serde(default = "default_gain")]
+        ratio: f32,
+        #[serde(default)]
+        fixed_frequency: Option<f32>,
+    },
+    /// `Svf` with cutoff at `cutoff` times the note frequency
+    Filter {
+        cutoff: f32,
+        resonance: f32,
+        #[serde(default)]
+        mode: FilterMode,
+    },
+    Vca {
+        gain: f32,
+    },
+    Envelope(AmpEnvelope),
+}
+
+/// Modules wired up by index, played as a `PatchGraph`.
+/// Connections to or from modules that don't exist are ignored.
+#[derive(Clone, Debug, Deserialize)]
+pub struct GraphPatch {
+    pub modules: Vec<ModuleDef>,
+    /// (from, to, input, amount)
+    pub connections: Vec<(usize, usize, Input, f32)>,
+    pub output: usize,
+    /// Module the note ends with, `output` if not set.
+    /// Only `Envelope`s end, song files are rejected if it's anything else.
+    #[serde(default)]
+    pub ends_with: Option<usize>,
+}
+
+impl GraphPatch {
+    fn play(&self, note: Note) -> RawSource {
+        let mut graph = PatchGraph::new();
+        for module in &self.modules {
+            match module {
+                ModuleDef::Oscillator {
+                    waveform,
+                    band_limited,
+                    ratio,
+                    fixed_frequency,
+                } => {
+                    let frequency = fixed_frequency.unwrap_or(note.frequency * ratio);
+                    graph.add_oscillator(waveform.oscillator(frequency, *band_limited), frequency)
+                }
+                ModuleDef::Filter {
+                    cutoff,
+                    resonance,
+                    mode,
+                } => graph.add_filter(*mode, note.frequency * cutoff, *resonance),
+                ModuleDef::Vca { gain } => graph.add_vca(*gain),
+                ModuleDef::Envelope(envelope) => graph.add_source(envelope.source(note)),
+            };
+        }
+
+        for &(from, to, input, amount) in &self.connections {
+            if let (Some(from), Some(to)) = (graph.node(from), graph.node(to)) {
+                graph.connect(from, to, input, amount);
+            }
+        }
+        if let Some(output) = graph.node(self.output) {
+            graph.set_output(output);
+        }
+        if let Some(node) = self.ends_with.and_then(|idx| graph.node(idx)) {
+            graph.ends_with(node);
+        }
+        graph.into_raw()
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.output >= self.modules.len() {
+            return Err(format!("output {} isn't a module", self.output));
+        }
+        // Only sources end, everything else plays for as long as the graph does
+        let end = self.ends_with.unwrap_or(self.output);
+        match self.modules.get(end) {
+            Some(ModuleDef::Envelope(_)) => Ok(()),
+            Some(_) => Err(format!(
+                "module {} never ends, ends_with needs to be an Envelope",
+                end
+            )),
+            None => Err(format!("ends_with {} isn't a module", end)),
+        }
+    }
+}
+
+/// Changes to an instrument's parameters, unset ones are left alone.
+/// Setting a cutoff adds a filter to patches without one.
+/// `GraphPatch`es are left as they are.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Overrides {
+    pub waveform: Option<Waveform>,
+    pub band_limited: Option<bool>,
+    pub duty: Option<Duty>,
+    pub cutoff: Option<f32>,
+    pub resonance: Option<f32>,
+    pub gain: Option<f32>,
+    pub amplitude: Option<f32>,
+    pub attack: Option<f32>,
+    pub hold: Option<f32>,
+    pub release: Option<f32>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub enum Instrument {
+    Patch(Patch),
+    Graph(GraphPatch),
+    Sampler(SamplerPatch),
+    Fm(FmPatch),
+    /// Plays `low` for notes under `frequency` hz, `high` for the rest
+    Split {
+        frequency: f32,
+        low: Box<Instrument>,
+        high: Box<Instrument>,
+    },
+}
+
+impl Instrument {
+    pub fn play(&self, note: Note) -> RawSource {
+        match self {
+            Instrument::Patch(patch) => patch.play(note),
+            Instrument::Graph(graph) => graph.play(note),
+            Instrument::Sampler(sampler) => sampler.play(note),
+            Instrument::Fm(fm) => fm.play(note),
+            Instrument::Split {
+                frequency,
+                low,
+                high,
+            } => {
+                if note.frequency < *frequency {
+                    low.play(note)
+                } else {
+                    high.play(note)
+                }
+            }
+        }
+    }
+
+    /// Check for settings that would play badly, eg: notes that never end
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Instrument::Patch(patch) => patch.validate(),
+            Instrument::Graph(graph) => graph.validate(),
+            Instrument::Sampler(sampler) => sampler.validate(),
+            Instrument::Fm(fm) => fm.validate(),
+            Instrument::Split { low, high, .. } => {
+                low.validate()?;
+                high.validate()
+            }
+        }
+    }
+
+    pub fn with_overrides(&self, overrides: &Overrides) -> Instrument {
+        match self {
+            Instrument::Patch(patch) => Instrument::Patch(patch.with_overrides(overrides)),
+            Instrument::Graph(graph) => Instrument::Graph(graph.clone()),
+            Instrument::Sampler(sampler) => Instrument::Sampler(sampler.with_overrides(overrides)),
+            Instrument::Fm(fm) => Instrument::Fm(fm.with_overrides(overrides)),
+            Instrument::Split {
+                frequency,
+                low,
+                high,
+            } => Instrument::Split {
+                frequency: *frequency,
+                low: Box::new(low.with_overrides(overrides)),
+                high: Box::new(high.with_overrides(overrides)),
+            },
+        }
+    }
+
+    /// Look up samples in `library`
+    pub fn bind_samples(&mut self, library: &SampleLibrary) {
+        match self {
+            Instrument::Patch(_) | Instrument::Graph(_) | Instrument::Fm(_) => {}
+            Instrument::Sampler(sampler) => sampler.library = library.clone(),
+            Instrument::Split { low, high, .. } => {
+                low.bind_samples(library);
+                high.bind_samples(library);
+            }
+        }
+    }
+}
+
+/// Instruments by name, for phrases and song files to use.
+/// Starts out with the built in instruments.
+/// Clones share the same instruments, so ones added later are seen by all of them.
+#[derive(Resource, Clone)]
+pub struct Instruments {
+    instruments: Arc<RwLock<HashMap<String, Arc<Instrument>>>>,
+    samples: SampleLibrary,
+}
+
+impl Default for Instruments {
+    fn default() -> Self {
+        let instruments = Instruments {
+            instruments: Arc::default(),
+            samples: SampleLibrary::default(),
+        };
+        instruments.insert("square_horn", square_horn());
+        instruments.insert("kick", kick());
+        instruments.insert("snare", snare());
+        instruments.insert("drum", drum());
+        instruments.insert("supersaw", supersaw());
+        instruments.insert("warble", warble());
+        instruments.insert("acid", acid());
+        instruments.insert("pluck", pluck());
+        instruments.insert("chip", chip());
+        instruments.insert("fuzz_bass", fuzz_bass());
+        instruments.insert("sample_drum", sample_drum());
+        instruments.insert("sample_hat", sample_hat());
+        instruments.insert("bell", bell());
+        instruments.insert("fm_bass", fm_bass());
+        instruments.insert("metal_hit", metal_hit());
+        instruments.insert("guitar", guitar());
+        instruments.insert("bowed", bowed());
+        instruments.insert("marimba", marimba());
+        instruments.insert("gb_pulse", gb_pulse());
+        instruments.insert("pwm_lead", pwm_lead());
+        instruments.insert("hat", hat());
+        instruments.insert("cymbal", cymbal());
+        instruments.insert("riser", riser());
+        instruments
+    }
+}
+
+impl Instruments {
+    pub fn get(&self, name: &str) -> Option<Arc<Instrument>> {
+        self.instruments.read().ok()?.get(name).cloned()
+    }
+
+    /// Add an instrument, replacing any with the same name
+    pub fn insert(&self, name: &str, mut instrument: Instrument) {
+        instrument.bind_samples(&self.samples);
+        if let Ok(mut instruments) = self.instruments.write() {
+            instruments.insert(name.to_string(), Arc::new(instrument));
+        }
+    }
+
+    /// Samples played by `Instrument::Sampler`, shared by every clone
+    pub fn samples(&self) -> &SampleLibrary {
+        &self.samples
+    }
+}
+
+pub fn square_horn() -> Instrument {
+    Instrument::Patch(Patch {
+        band_limited: true,
+        filter: Some(Filter {
+            cutoff: 0.5,
+            resonance: 1.0,
+            cv: Some(Cv::Envelope {
+                amplitude: 0.3,
+                attack: 0.1,
+                hold: 0.05,
+                release: 0.1,
+            }),
+            mode: None,
+            resonance_cv: None,
+        }),
+        amp: AmpEnvelope {
+            amplitude: 0.3,
+            attack: 0.05,
+            hold: 0.05,
+            release: 0.2,
+            held: true,
+        },
+        ..Patch::new(Waveform::Square)
+    })
+}
+
+pub fn kick() -> Instrument {
+    Instrument::Patch(Patch {
+        pitch_cv: Some(Cv::Envelope {
+            amplitude: 0.02,
+            attack: 0.0,
+            hold: 0.0,
+            release: 0.2,
+        }),
+        gain: 2.0,
+        amp: AmpEnvelope {
+            amplitude: 1.0,
+            attack: 0.001,
+            hold: 0.1,
+            release: 0.3,
+            held: false,
+        },
+        ..Patch::new(Waveform::Triangle)
+    })
+}
+
+pub fn snare() -> Instrument {
+    Instrument::Patch(Patch {
+        fixed_frequency: Some(20000.),
+        amp: AmpEnvelope {
+            amplitude: 0.2,
+            attack: 0.001,
+            hold: 0.0,
+            release: 0.3,
+            held: false,
+        },
+        ..Patch::new(Waveform::Noise)
+    })
+}
+
+// TODO: Convert all of these to voltage for easier drum type selection
+pub fn drum() -> Instrument {
+    Instrument::Split {
+        frequency: 100.,
+        low: Box::new(kick()),
+        high: Box::new(snare()),
+    }
+}
+
+pub fn supersaw() -> Instrument {
+    Instrument::Patch(Patch {
+        band_limited: true,
+        filter: Some(Filter {
+            cutoff: 0.5,
+            resonance: 1.0,
+            cv: Some(Cv::Envelope {
+                amplitude: 0.3,
+                attack: 0.1,
+                hold: 0.05,
+                release: 0.2,
+            }),
+            mode: None,
+            resonance_cv: None,
+        }),
+        amp: AmpEnvelope {
+            amplitude: 0.3,
+            attack: 0.05,
+            hold: 0.05,
+            release: 0.2,
+            held: true,
+        },
+        ..Patch::new(Waveform::SuperSaw)
+    })
+}
+
+pub fn warble() -> Instrument {
+    Instrument::Patch(Patch {
+        band_limited: true,
+        pitch_cv: Some(Cv::Lfo {
+            waveform: Waveform::Triangle,
+            frequency: 40.,
+            depth: 0.2,
+        }),
+        amp: AmpEnvelope {
+            amplitude: 0.3,
+            attack: 0.01,
+            hold: 0.05,
+            release: 0.25,
+            held: true,
+        },
+        ..Patch::new(Waveform::Ramp)
+    })
+}
+
+/// Resonant filter sweep on every note, for basslines
+pub fn acid() -> Instrument {
+    Instrument::Patch(Patch {
+        band_limited: true,
+        filter: Some(Filter {
+            cutoff: 1.0,
+            resonance: 0.85,
+            cv: Some(Cv::Envelope {
+                amplitude: 0.35,
+                attack: 0.0,
+                hold: 0.02,
+                release: 0.15,
+            }),
+            mode: Some(FilterMode::Lowpass),
+            resonance_cv: None,
+        }),
+        amp: AmpEnvelope {
+            amplitude: 0.3,
+            attack: 0.005,
+            hold: 0.1,
+            release: 0.05,
+            held: true,
+        },
+        ..Patch::new(Waveform::Saw)
+    })
+}
+
+/// Short decaying filter, like a plucked string
+pub fn pluck() -> Instrument {
+    Instrument::Patch(Patch {
+        band_limited: true,
+        filter: Some(Filter {
+            cutoff: 1.0,
+            resonance: 0.3,
+            cv: Some(Cv::Envelope {
+                amplitude: 0.4,
+                attack: 0.0,
+                hold: 0.0,
+                release: 0.1,
+            }),
+            mode: Some(FilterMode::Lowpass),
+            resonance_cv: None,
+        }),
+        amp: AmpEnvelope {
+            amplitude: 0.3,
+            attack: 0.002,
+            hold: 0.0,
+            release: 0.3,
+            held: false,
+        },
+        ..Patch::new(Waveform::Square)
+    })
+}
+
+/// Crushed square, like an old console
+pub fn chip() -> Instrument {
+    Instrument::Patch(Patch {
+        amp: AmpEnvelope {
+            amplitude: 0.25,
+            attack: 0.001,
+            hold: 0.08,
+            release: 0.05,
+            held: true,
+        },
+        effects: vec![EffectDef::Bitcrusher {
+            bits: 6,
+            downsample: 4.,
+        }],
+        ..Patch::new(Waveform::Square)
+    })
+}
+
+/// Saturated saw, for techno basslines
+pub fn fuzz_bass() -> Instrument {
+    Instrument::Patch(Patch {
+        band_limited: true,
+        filter: Some(Filter {
+            cutoff: 4.0,
+            resonance: 0.2,
+            cv: None,
+            mode: Some(FilterMode::Lowpass),
+            resonance_cv: None,
+        }),
+        amp: AmpEnvelope {
+            amplitude: 0.5,
+            attack: 0.005,
+            hold: 0.1,
+            release: 0.08,
+            held: true,
+        },
+        effects: vec![EffectDef::Waveshaper {
+            shape: Shape::Soft,
+            drive: 4.,
+            level: 0.4,
+        }],
+        ..Patch::new(Waveform::Saw)
+    })
+}
+
+/// Kick and snare one-shots from `assets/samples`, split like `drum`
+pub fn sample_drum() -> Instrument {
+    Instrument::Split {
+        frequency: 100.,
+        low: Box::new(Instrument::Sampler(SamplerPatch {
+            gain: 0.6,
+            ..SamplerPatch::new("samples/kick.wav")
+        })),
+        high: Box::new(Instrument::Sampler(SamplerPatch {
+            gain: 0.4,
+            ..SamplerPatch::new("samples/snare.wav")
+        })),
+    }
+}
+
+/// Closed hi-hat one-shot from `assets/samples`
+pub fn sample_hat() -> Instrument {
+    Instrument::Sampler(SamplerPatch {
+        gain: 0.3,
+        ..SamplerPatch::new("samples/hat.wav")
+    })
+}
+
+/// Two operator bell, the modulator's brightness dies away first
+pub fn bell() -> Instrument {
+    Instrument::Fm(FmPatch {
+        algorithm: FmAlgorithm::Stack,
+        operators: vec![
+            OperatorDef {
+                ratio: 3.5,
+                index: 4.0,
+                feedback: 0.,
+                envelope: AmpEnvelope {
+                    amplitude: 1.0,
+                    attack: 0.001,
+                    hold: 0.0,
+                    release: 0.8,
+                    held: false,
+                },
+            },
+            OperatorDef {
+                ratio: 1.0,
+                index: 1.0,
+                feedback: 0.,
+                envelope: AmpEnvelope {
+                    amplitude: 0.5,
+                    attack: 0.001,
+                    hold: 0.0,
+                    release: 1.5,
+                    held: false,
+                },
+            },
+        ],
+        pitch_cv: None,
+        gain: 1.0,
+        effects: Vec::new(),
+    })
+}
+
+/// Punchy bass, a gritty feedback modulator at the same pitch
+pub fn fm_bass() -> Instrument {
+    Instrument::Fm(FmPatch {
+        algorithm: FmAlgorithm::Stack,
+        operators: vec![
+            OperatorDef {
+                ratio: 1.0,
+                index: 2.5,
+                feedback: 0.4,
+                envelope: AmpEnvelope {
+                    amplitude: 1.0,
+                    attack: 0.001,
+                    hold: 0.02,
+                    release: 0.15,
+                    held: false,
+                },
+            },
+            OperatorDef {
+                ratio: 1.0,
+                index: 1.0,
+                feedback: 0.,
+                envelope: AmpEnvelope {
+                    amplitude: 0.6,
+                    attack: 0.002,
+                    hold: 0.1,
+                    release: 0.1,
+                    held: true,
+                },
+            },
+        ],
+        pitch_cv: None,
+        gain: 1.0,
+        effects: Vec::new(),
+    })
+}
+
+/// Short clang, two inharmonic modulators into one carrier
+pub fn metal_hit() -> Instrument {
+    let decay = |amplitude, release| AmpEnvelope {
+        amplitude,
+        attack: 0.0005,
+        hold: 0.0,
+        release,
+        held: false,
+    };
+    Instrument::Fm(FmPatch {
+        algorithm: FmAlgorithm::Branch,
+        operators: vec![
+            OperatorDef {
+                ratio: 1.41,
+                index: 6.0,
+                feedback: 0.,
+                envelope: decay(1.0, 0.15),
+            },
+            OperatorDef {
+                ratio: 2.76,
+                index: 3.0,
+                feedback: 0.,
+                envelope: decay(1.0, 0.3),
+            },
+            OperatorDef {
+                ratio: 1.0,
+                index: 1.0,
+                feedback: 0.,
+                envelope: decay(0.5, 0.35),
+            },
+        ],
+        pitch_cv: None,
+        gain: 1.0,
+        effects: Vec::new(),
+    })
+}
+
+/// Karplus-Strong plucked string, rings on through ties
+pub fn guitar() -> Instrument {
+    Instrument::Patch(Patch {
+        amp: AmpEnvelope {
+            amplitude: 0.8,
+            attack: 0.001,
+            hold: 0.6,
+            release: 0.3,
+            held: true,
+        },
+        ..Patch::new(Waveform::Pluck)
+    })
+}
+
+/// Karplus-Strong string bowed with noise rather than plucked
+pub fn bowed() -> Instrument {
+    Instrument::Patch(Patch {
+        strike: 0.0,
+        excitation: Some(AmpEnvelope {
+            amplitude: 0.15,
+            attack: 0.15,
+            hold: 0.3,
+            release: 0.2,
+            held: true,
+        }),
+        amp: AmpEnvelope {
+            amplitude: 1.0,
+            attack: 0.01,
+            hold: 0.5,
+            release: 0.4,
+            held: true,
+        },
+        ..Patch::new(Waveform::Pluck)
+    })
+}
+
+/// Struck wooden bar
+pub fn marimba() -> Instrument {
+    Instrument::Patch(Patch {
+        amp: AmpEnvelope {
+            amplitude: 1.0,
+            attack: 0.0,
+            hold: 0.5,
+            release: 0.2,
+            held: false,
+        },
+        ..Patch::new(Waveform::Bar)
+    })
+}
+
+/// Thin 12.5% pulse, like the Game Boy pulse channels
+pub fn gb_pulse() -> Instrument {
+    Instrument::Patch(Patch {
+        duty: Some(Duty::Eighth),
+        amp: AmpEnvelope {
+            amplitude: 0.25,
+            attack: 0.001,
+            hold: 0.1,
+            release: 0.1,
+            held: true,
+        },
+        ..Patch::new(Waveform::Square)
+    })
+}
+
+/// Pulse width swept by an LFO, thick and moving
+pub fn pwm_lead() -> Instrument {
+    Instrument::Patch(Patch {
+        band_limited: true,
+        pwm: Some(Cv::Lfo {
+            waveform: Waveform::Triangle,
+            frequency: 3.0,
+            depth: 0.7,
+        }),
+        amp: AmpEnvelope {
+            amplitude: 0.3,
+            attack: 0.02,
+            hold: 0.2,
+            release: 0.2,
+            held: true,
+        },
+        ..Patch::new(Waveform::Square)
+    })
+}
+
+/// Short burst of highpassed white noise
+pub fn hat() -> Instrument {
+    Instrument::Patch(Patch {
+        fixed_frequency: Some(1000.),
+        filter: Some(Filter {
+            cutoff: 7.0,
+            resonance: 0.1,
+            cv: None,
+            mode: Some(FilterMode::Highpass),
+            resonance_cv: None,
+        }),
+        amp: AmpEnvelope {
+            amplitude: 0.25,
+            attack: 0.001,
+            hold: 0.0,
+            release: 0.05,
+            held: false,
+        },
+        ..Patch::new(Waveform::WhiteNoise)
+    })
+}
+
+/// Metallic 7 bit noise, ringing on
+pub fn cymbal() -> Instrument {
+    Instrument::Patch(Patch {
+        fixed_frequency: Some(12000.),
+        filter: Some(Filter {
+            cutoff: 0.5,
+            resonance: 0.2,
+            cv: None,
+            mode: Some(FilterMode::Highpass),
+            resonance_cv: None,
+        }),
+        amp: AmpEnvelope {
+            amplitude: 0.2,
+            attack: 0.001,
+            hold: 0.0,
+            release: 0.8,
+            held: false,
+        },
+        ..Patch::new(Waveform::ShortNoise)
+    })
+}
+
+/// Pink noise swelling up through a bandpass sweep
+pub fn riser() -> Instrument {
+    Instrument::Patch(Patch {
+        fixed_frequency: Some(1000.),
+        filter: Some(Filter {
+            cutoff: 0.5,
+            resonance: 0.6,
+            cv: Some(Cv::Envelope {
+                amplitude: 0.3,
+                attack: 2.0,
+                hold: 0.0,
+                release: 0.1,
+            }),
+            mode: Some(FilterMode::Bandpass),
+            resonance_cv: None,
+        }),
+        amp: AmpEnvelope {
+            amplitude: 0.8,
+            attack: 1.8,
+            hold: 0.2,
+            release: 0.1,
+            held: false,
+        },
+        ..Patch::new(Waveform::PinkNoise)
+    })
+}
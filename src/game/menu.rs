@@ -1,3 +1,4 @@
+use super::assets::Songs;
 use super::audio::audio_generator::*;
 use super::audio::Audio;
 use super::song::{mary_song, techno, Song};
@@ -139,6 +140,8 @@ fn click_play_button(
     mut commands: Commands,
     button_colors: Res<ButtonColors>,
     mut state: ResMut<NextState<GameState>>,
+    mut songs: ResMut<Songs>,
+    song_assets: Res<Assets<Song>>,
     mut interation_query: Query<
         (&Interaction, &mut BackgroundColor, &WhichButton),
         (Changed<Interaction>, With<Button>),
@@ -147,10 +150,14 @@ fn click_play_button(
     for (interaction, mut color, which) in &mut interation_query {
         match *interaction {
             Interaction::Clicked => {
-                match which {
-                    WhichButton::Mary => commands.insert_resource(mary_song()),
-                    WhichButton::Techno => commands.insert_resource(techno()),
-                }
+                let (handle, built_in): (_, fn() -> Song) = match which {
+                    WhichButton::Mary => (songs.mary.clone(), mary_song),
+                    WhichButton::Techno => (songs.techno.clone(), techno),
+                };
+                // Fall back to the built in song if the file isn't loaded (or failed to load)
+                let song = song_assets.get(&handle).cloned().unwrap_or_else(built_in);
+                commands.insert_resource(song);
+                songs.current = handle;
                 state.set(GameState::Playing);
             }
             Interaction::Hovered => {
@@ -234,13 +241,13 @@ fn game_over(
         EndState::Winner => {
             let vco = Vco::from_oscillator(SuperSaw::new(1.), 130.81);
             let vca = Vca::new(vco, Envelope::new(0.2, 0.1, 0.2, 1.0));
-            audio.play(vca.as_raw());
+            audio.play(vca.into_raw());
             let vco = Vco::from_oscillator(SuperSaw::new(1.), 196.00);
             let vca = Vca::new(vco, Envelope::new(0.2, 0.1, 0.2, 1.0));
-            audio.play(vca.as_raw());
+            audio.play(vca.into_raw());
             let vco = Vco::from_oscillator(SuperSaw::new(1.), 261.63);
             let vca = Vca::new(vco, Envelope::new(0.2, 0.1, 0.2, 1.0));
-            audio.play(vca.as_raw());
+            audio.play(vca.into_raw());
         }
     }
 }
@@ -3,18 +3,24 @@ use audio::audio_generator::*;
 use audio::audio_output::AudioOutput;
 use audio::Audio;
 use bevy::prelude::*;
+use std::collections::HashMap;
 
 pub mod animation;
 pub mod assets;
 pub mod audio;
 pub mod cannon;
 pub mod enemy;
+pub mod instrument;
 pub mod menu;
 pub mod player;
 pub mod song;
+pub mod song_file;
 pub mod world;
 
-pub struct Plugin;
+#[derive(Default)]
+pub struct Plugin {
+    pub audio: audio::AudioPlugin,
+}
 
 #[derive(States, Default, Clone, Eq, PartialEq, Debug, Hash)]
 pub enum GameState {
@@ -33,14 +39,12 @@ enum EndState {
 impl bevy::app::Plugin for Plugin {
     fn build(&self, app: &mut App) {
         app.add_state::<GameState>()
-            .insert_resource(AudioOutput::default())
-            .insert_resource(Audio::default())
             .add_startup_system(spawn_camera)
             .add_system(button_system);
         app.add_plugin(menu::MenuPlugin)
             .add_plugin(assets::AssetPlugin)
             .add_plugin(animation::AnimationPlugin)
-            .add_plugin(audio::AudioPlugin)
+            .add_plugin(self.audio.clone())
             .add_plugin(cannon::CannonPlugin)
             .add_plugin(enemy::EnemyPlugin)
             .add_plugin(player::PlayerPlugin)
@@ -52,35 +56,58 @@ fn spawn_camera(mut commands: Commands) {
     commands.spawn(Camera2dBundle::default());
 }
 
-fn button_system(keyboard_input: Res<Input<KeyCode>>, audio: ResMut<Audio>) {
+fn button_system(
+    keyboard_input: Res<Input<KeyCode>>,
+    audio: ResMut<Audio>,
+    audio_output: Res<AudioOutput>,
+    mut held_keys: Local<HashMap<KeyCode, GateHandle>>,
+) {
     if keyboard_input.just_pressed(KeyCode::Space) {
         println!("Here");
         let vco = Vco::from_oscillator(SuperSaw::new(440.), 220.);
         let vca = Vca::new(vco, Envelope::new(0.2, 0.1, 0.2, 1.0));
 
-        audio.play(vca.as_raw());
+        audio.play(vca.into_raw());
+    }
+
+    if keyboard_input.just_pressed(KeyCode::M) {
+        audio_output.set_muted(!audio_output.muted());
+    }
+
+    if keyboard_input.just_pressed(KeyCode::P) {
+        audio_output.set_paused(!audio_output.paused());
+    }
+
+    // Release notes for keys that are let go
+    for key_code in keyboard_input.get_just_released() {
+        if let Some(gate) = held_keys.remove(key_code) {
+            gate.release();
+        }
     }
 
-    let mut keys = Vec::<u32>::new();
+    let mut keys = Vec::<(KeyCode, u32)>::new();
 
     if keyboard_input.just_pressed(KeyCode::Z) {
-        keys.push(0);
+        keys.push((KeyCode::Z, 0));
     }
     if keyboard_input.just_pressed(KeyCode::X) {
-        keys.push(2);
+        keys.push((KeyCode::X, 2));
     }
     if keyboard_input.just_pressed(KeyCode::C) {
-        keys.push(4);
+        keys.push((KeyCode::C, 4));
     }
     if keyboard_input.just_pressed(KeyCode::V) {
-        keys.push(5);
+        keys.push((KeyCode::V, 5));
     }
     if keyboard_input.just_pressed(KeyCode::B) {
-        keys.push(7);
+        keys.push((KeyCode::B, 7));
     }
 
-    for key in keys {
+    for (key_code, key) in keys {
         let frequency = frequency_per_volt(key as f32 / 120.0 + 0.2);
+        // Hold the note for as long as the key is down
+        let (gate, gate_handle) = Gate::new();
+        held_keys.insert(key_code, gate_handle);
         let vca = Vca::new(
             Vco::new(
                 //Vcf::new(SquareWave::new(frequency), frequency, 1.41),
@@ -88,9 +115,9 @@ fn button_system(keyboard_input: Res<Input<KeyCode>>, audio: ResMut<Audio>) {
                 frequency / 2.,
                 Envelope::new(0.4, 0.2, 0.05, 0.2),
             ),
-            Envelope::new(0.3, 0.1, 0.05, 0.2),
+            Adsr::new(0.3, 0.1, 0.05, 0.6, 0.2, gate),
         );
-        audio.play(vca.as_raw());
+        audio.play(vca.into_raw());
         //let osc = Attenuator::new(TriangleWave::new(frequency), 2.0);
 
         /* Kick
@@ -108,7 +135,7 @@ fn button_system(keyboard_input: Res<Input<KeyCode>>, audio: ResMut<Audio>) {
         let vco = NoiseLFSR::new(20000.);
         let osc = vco;
         let vca = Vca::new(osc, snare_env);
-        audio.play(vca.as_raw());
+        audio.play(vca.into_raw());
         */
     }
 }
@@ -1,7 +1,8 @@
 use super::animation::{Animated, Animation, AnimationFrame, AnimationMarker};
 use super::assets::Sprites;
+use super::audio::effects::{Fx, Reverb};
 use super::audio::{audio_generator::*, Audio};
-use super::world::{Bullet, BulletType, Moveable, Wall, WorldPosition};
+use super::world::{Bullet, BulletType, Moveable, Wall, World, WorldPosition};
 use super::{EndState, GameState};
 use bevy::prelude::*;
 use bevy::sprite::collide_aabb::{collide, Collision};
@@ -270,6 +271,7 @@ fn player_bullet_system(
     bullet_query: Query<(Entity, &WorldPosition, &Bullet), (With<Bullet>, Without<Player>)>,
     mut state: ResMut<NextState<GameState>>,
     audio: Res<Audio>,
+    world_query: Query<&World>,
 ) {
     let bullet_size = Vec2::new(8., 8.);
     for (player_position, mut player, mut animated) in &mut player_query {
@@ -294,9 +296,10 @@ fn player_bullet_system(
                 animated.push_animation(PlayerAnimations::Hurt);
                 commands.entity(entity).despawn();
 
-                let vco = Vco::new(RampWave::new(440.), 440., SawWave::new(20.));
-                let vca = Vca::new(vco, Envelope::new(0.2, 0.1, 0.0, 0.1));
-                audio.play(vca.as_raw());
+                let pan = world_query
+                    .get_single()
+                    .map_or(0., |world| world.pan(player_position.position));
+                audio.play(hit_sound(pan));
 
                 if player.health <= 0 {
                     commands.insert_resource(EndState::GameOver);
@@ -307,6 +310,14 @@ fn player_bullet_system(
     }
 }
 
+/// Played when a bullet hits the player, panned to where they are
+fn hit_sound(pan: f32) -> RawSource {
+    let vco = Vco::new(RampWave::new(440.), 440., SawWave::new(20.));
+    let vca = Vca::new(vco, Envelope::new(0.2, 0.1, 0.0, 0.1));
+    let room = Fx::new(vca, Reverb::new(0.3, 0.5, 0.25));
+    Pan::new(room, pan).into_raw()
+}
+
 fn player_animation_system(
     time: Res<Time>,
     mut player_query: Query<(
@@ -344,3 +355,44 @@ fn player_teardown(mut commands: Commands, query: Query<Entity, With<Player>>) {
         commands.entity(player).despawn();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::audio::backend::{Backend, CaptureBuffer};
+    use crate::game::audio::AudioPlugin;
+    use bevy::time::TimeUpdateStrategy;
+    use std::time::{Duration, Instant};
+
+    /// Run `frames` game frames at 60fps, `frame` counts every frame run so far
+    fn run_frames(app: &mut App, start: Instant, frame: &mut u32, frames: u32) {
+        for _ in 0..frames {
+            *frame += 1;
+            let now = start + Duration::from_secs_f32(*frame as f32 / 60.);
+            app.insert_resource(TimeUpdateStrategy::ManualInstant(now));
+            app.update();
+        }
+    }
+
+    #[test]
+    fn hit_sound_is_played() {
+        let buffer = CaptureBuffer::default();
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins).add_plugin(AudioPlugin {
+            backend: Backend::Capture(buffer.clone()),
+            ..default()
+        });
+
+        let start = Instant::now();
+        let mut frame = 0;
+        // Wait out the startup sound
+        run_frames(&mut app, start, &mut frame, 150);
+        buffer.clear();
+        run_frames(&mut app, start, &mut frame, 10);
+        assert_eq!(buffer.peak(), 0.);
+
+        app.world.resource::<Audio>().play(hit_sound(0.));
+        run_frames(&mut app, start, &mut frame, 30);
+        assert!(buffer.peak() > 0.);
+    }
+}
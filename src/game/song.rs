@@ -1,31 +1,157 @@
 use super::audio::audio_generator::*;
+use super::audio::mixer::Sends;
+use super::instrument::{self, AmpEnvelope, Instrument, Patch};
 use bevy::prelude::*;
+use bevy::reflect::TypeUuid;
+use serde::Deserialize;
+use std::sync::Arc;
 
-pub type Notes = [Option<(i32, RawSource)>; 4];
+/// Value and source of the note each track starts on a step
+pub type Notes<T = RawSource> = [Option<(i32, T)>; 4];
 
 // TODO: allow offset to eigth/quarter?
 
-#[derive(Resource)]
+/// Tempo of a song unless set with `Song::with_bpm`
+const DEFAULT_BPM: f32 = 120.;
+
+/// Octave of note letters written without one, `c` is C4
+const DEFAULT_OCTAVE: i32 = 4;
+
+/// What a patch is asked to play.
+#[derive(Clone, Copy, Debug)]
+pub struct Note {
+    pub frequency: f32,
+    /// Seconds the note is held past its own step, from ties
+    pub sustain: f32,
+}
+
+impl Note {
+    pub fn new(frequency: f32) -> Self {
+        Note {
+            frequency,
+            sustain: 0.,
+        }
+    }
+}
+
+/// Cheap to clone, clones share the same tracks.
+/// Loaded as an asset from `.bjsong` files, see `song_file`.
+#[derive(Resource, Clone, TypeUuid)]
+#[uuid = "5B0B9E0A-3F3C-4C52-9B0E-7A6E0C2C1E4D"]
 pub struct Song {
-    tracks: Vec<Track>,
+    tracks: Arc<Vec<Track>>,
+    /// Beats (quarter notes) per minute
+    bpm: f32,
+    /// Tempo overrides, by chain
+    chain_bpm: Vec<Option<f32>>,
+    /// Percentage of a 16th that odd 16ths are delayed by, 0 (straight) to 100
+    swing: f32,
+    /// Effect sends, by track
+    sends: Vec<Sends>,
+    /// Mixer gain, by track
+    gains: Vec<f32>,
 }
 
 impl Song {
-    pub fn note(&self, idx: usize, chain: usize) -> Notes {
-        let mut notes: Notes = [None, None, None, None];
+    pub fn new(tracks: Vec<Track>) -> Self {
+        Song {
+            tracks: Arc::new(tracks),
+            bpm: DEFAULT_BPM,
+            chain_bpm: Vec::new(),
+            swing: 0.,
+            sends: Vec::new(),
+            gains: Vec::new(),
+        }
+    }
+
+    /// Tempos must be positive, song files are checked by `song_file::parse_song`
+    pub fn with_bpm(mut self, bpm: f32) -> Self {
+        self.bpm = bpm;
+        self
+    }
+
+    /// Change tempo while `chain` is playing
+    pub fn with_chain_bpm(mut self, chain: usize, bpm: f32) -> Self {
+        if chain >= self.chain_bpm.len() {
+            self.chain_bpm.resize(chain + 1, None);
+        }
+        self.chain_bpm[chain] = Some(bpm);
+        self
+    }
+
+    pub fn with_swing(mut self, swing: f32) -> Self {
+        self.swing = swing.clamp(0., 100.);
+        self
+    }
+
+    pub fn with_sends(mut self, track: usize, sends: Sends) -> Self {
+        if track >= self.sends.len() {
+            self.sends.resize(track + 1, Sends::default());
+        }
+        self.sends[track] = sends;
+        self
+    }
+
+    pub fn sends(&self, track: usize) -> Sends {
+        self.sends.get(track).copied().unwrap_or_default()
+    }
+
+    pub fn with_gain(mut self, track: usize, gain: f32) -> Self {
+        if track >= self.gains.len() {
+            self.gains.resize(track + 1, 1.0);
+        }
+        self.gains[track] = gain;
+        self
+    }
+
+    /// 1.0 unless set with `with_gain`
+    pub fn gain(&self, track: usize) -> f32 {
+        self.gains.get(track).copied().unwrap_or(1.0)
+    }
+
+    /// Tempo of `chain`, in beats per minute
+    pub fn bpm(&self, chain: usize) -> f32 {
+        self.chain_bpm
+            .get(chain)
+            .copied()
+            .flatten()
+            .unwrap_or(self.bpm)
+    }
+
+    /// Length of a 16th note in `chain`, in seconds
+    pub fn step_time(&self, chain: usize) -> f32 {
+        // 60 seconds/min, 4/beat (16th notes)
+        60. / self.bpm(chain) / 4.
+    }
+
+    /// Time in seconds that the 16th at `idx` is played after the beat grid
+    pub fn swing_delay(&self, idx: usize, chain: usize) -> f32 {
+        if idx % 2 == 1 {
+            self.step_time(chain) * self.swing / 100.
+        } else {
+            0.
+        }
+    }
+
+    /// `play` builds each note from its phrase's instrument and its velocity, eg: `play_note`
+    pub fn note<T, F>(&self, idx: usize, chain: usize, mut play: F) -> Notes<T>
+    where
+        F: FnMut(&Arc<Instrument>, Note, f32) -> T,
+    {
+        let mut notes: Notes<T> = [None, None, None, None];
         for i in 0..self.tracks.len() {
             if i >= 4 {
                 break;
             }
 
-            notes[i] = self.tracks[i].note(idx, chain);
+            notes[i] = self.tracks[i].note(idx, chain, self.step_time(chain), &mut play);
         }
         notes
     }
 
     pub fn len(&self, chain: usize) -> usize {
         let mut max = 0;
-        for track in &self.tracks {
+        for track in self.tracks.iter() {
             let len = track.len(chain);
             if len > max {
                 max = len;
@@ -36,7 +162,7 @@ impl Song {
 
     pub fn max_chains(&self) -> usize {
         let mut max = 0;
-        for track in &self.tracks {
+        for track in self.tracks.iter() {
             let len = track.chains.len();
             if len > max {
                 max = len;
@@ -51,11 +177,18 @@ pub struct Track {
 }
 
 impl Track {
-    fn note(&self, idx: usize, chain: usize) -> Option<(i32, RawSource)> {
+    pub fn new(chains: Vec<Chain>) -> Self {
+        Track { chains }
+    }
+
+    fn note<T, F>(&self, idx: usize, chain: usize, step_time: f32, play: F) -> Option<(i32, T)>
+    where
+        F: FnMut(&Arc<Instrument>, Note, f32) -> T,
+    {
         if chain >= self.chains.len() {
             None
         } else {
-            self.chains[chain].note(idx)
+            self.chains[chain].note(idx, step_time, play)
         }
     }
 
@@ -73,7 +206,14 @@ pub struct Chain {
 }
 
 impl Chain {
-    fn note(&self, mut idx: usize) -> Option<(i32, RawSource)> {
+    pub fn new(phrases: Vec<Phrase>) -> Self {
+        Chain { phrases }
+    }
+
+    fn note<T, F>(&self, mut idx: usize, step_time: f32, play: F) -> Option<(i32, T)>
+    where
+        F: FnMut(&Arc<Instrument>, Note, f32) -> T,
+    {
         if self.len() == 0 {
             return None;
         }
@@ -84,7 +224,7 @@ impl Chain {
 
         for phrase in &self.phrases {
             if idx < phrase.len() {
-                return phrase.note(idx);
+                return phrase.note(idx, step_time, play);
             }
             idx -= phrase.len();
         }
@@ -102,6 +242,7 @@ impl Chain {
     }
 }
 
+#[derive(Deserialize)]
 pub enum PhraseType {
     Quarter,
     Eigth,
@@ -124,292 +265,380 @@ impl PhraseType {
     }
 }
 
+/// A step of a phrase, parsed from its notes
+enum Step {
+    Rest,
+    /// Holds the note before it for another step
+    Tie,
+    Note {
+        /// Pitch, see `frequency_per_volt`
+        voltage: f32,
+        /// Passed on with the note, used for bullet lanes
+        value: i32,
+        velocity: f32,
+    },
+}
+
+impl Step {
+    fn parse_all(notes: &str) -> Vec<Step> {
+        let mut steps = Vec::new();
+        let mut chars = notes.chars();
+        while let Some(c) = chars.next() {
+            let step = match c {
+                '-' => Step::Tie,
+                '[' => {
+                    let token: String = chars.by_ref().take_while(|c| *c != ']').collect();
+                    Step::parse_bracketed(&token).unwrap_or(Step::Rest)
+                }
+                '0'..='9' => {
+                    let value = c as i32 - '0' as i32;
+                    Step::Note {
+                        voltage: value as f32 / 120.,
+                        value,
+                        velocity: 1.0,
+                    }
+                }
+                _ => match semitone(c) {
+                    Some(value) => Step::note(value, DEFAULT_OCTAVE, 1.0),
+                    None => Step::Rest,
+                },
+            };
+            steps.push(step);
+        }
+        steps
+    }
+
+    /// `<letter>[#|b][octave][v<velocity>]`, eg: `c#5`, `eb3`, `gv5`
+    fn parse_bracketed(token: &str) -> Option<Step> {
+        let mut chars = token.chars().peekable();
+        let mut semitone = semitone(chars.next()?.to_ascii_lowercase())?;
+        match chars.peek() {
+            Some('#') => {
+                semitone += 1;
+                chars.next();
+            }
+            Some('b') => {
+                semitone -= 1;
+                chars.next();
+            }
+            _ => (),
+        }
+
+        let mut octave = DEFAULT_OCTAVE;
+        if let Some(digit) = chars.peek().and_then(|c| c.to_digit(10)) {
+            octave = digit as i32;
+            chars.next();
+        }
+
+        let mut velocity = 1.0;
+        if chars.peek() == Some(&'v') {
+            chars.next();
+            velocity = chars.next()?.to_digit(10)? as f32 / 9.;
+        }
+
+        if chars.next().is_some() {
+            return None;
+        }
+        Some(Step::note(semitone, octave, velocity))
+    }
+
+    fn note(semitone: i32, octave: i32, velocity: f32) -> Step {
+        // 0 volts is C2, 0.1 volts per octave
+        let voltage = ((octave - 2) * 12 + semitone) as f32 / 120.;
+        Step::Note {
+            voltage,
+            value: semitone.rem_euclid(12),
+            velocity,
+        }
+    }
+}
+
+fn semitone(letter: char) -> Option<i32> {
+    match letter {
+        'c' => Some(0),
+        'd' => Some(2),
+        'e' => Some(4),
+        'f' => Some(5),
+        'g' => Some(7),
+        'a' => Some(9),
+        'b' => Some(11),
+        _ => None,
+    }
+}
+
+/// Notes, one character per step:
+/// - `c d e f g a b`: note in octave 4
+/// - `0` to `9`: semitones up from C2
+/// - `-`: tie, holds the previous note
+/// - `[...]`: note with accidental, octave and velocity, eg: `[c#5]`, `[eb3v5]`
+/// - anything else is a rest
 pub struct Phrase {
-    notes: &'static str,
+    steps: Vec<Step>,
     phrase_type: PhraseType,
-    sound_gen: Box<dyn Fn(f32) -> RawSource + Sync + Send + 'static>,
+    instrument: Arc<Instrument>,
 }
 
 impl Phrase {
     fn silence() -> Self {
         Self {
-            notes: "____",
+            steps: Step::parse_all("____"),
             phrase_type: PhraseType::Quarter,
-            sound_gen: Box::new(|_| Envelope::new(0.0, 0.0, 0.0, 0.0).as_raw()),
+            instrument: Arc::new(Instrument::Patch(Patch {
+                amp: AmpEnvelope {
+                    amplitude: 0.0,
+                    attack: 0.0,
+                    hold: 0.0,
+                    release: 0.0,
+                    held: false,
+                },
+                ..Patch::new(Waveform::Square)
+            })),
         }
     }
 
-    fn new<G>(notes: &'static str, phrase_type: PhraseType, sound_gen: G) -> Self
-    where
-        G: Fn(f32) -> RawSource + Sync + Send + 'static,
-    {
+    pub fn new(notes: &str, phrase_type: PhraseType, instrument: Arc<Instrument>) -> Self {
         Self {
-            notes,
+            steps: Step::parse_all(notes),
             phrase_type,
-            sound_gen: Box::new(sound_gen),
+            instrument,
         }
     }
 
-    fn quarter<G>(notes: &'static str, sound_gen: G) -> Self
-    where
-        G: Fn(f32) -> RawSource + Sync + Send + 'static,
-    {
-        Self::new(notes, PhraseType::Quarter, sound_gen)
+    fn quarter(notes: &str, instrument: &Arc<Instrument>) -> Self {
+        Self::new(notes, PhraseType::Quarter, instrument.clone())
     }
 
-    fn eigth<G>(notes: &'static str, sound_gen: G) -> Self
-    where
-        G: Fn(f32) -> RawSource + Sync + Send + 'static,
-    {
-        Self::new(notes, PhraseType::Eigth, sound_gen)
+    fn eigth(notes: &str, instrument: &Arc<Instrument>) -> Self {
+        Self::new(notes, PhraseType::Eigth, instrument.clone())
     }
 
-    fn sixteenth<G>(notes: &'static str, sound_gen: G) -> Self
-    where
-        G: Fn(f32) -> RawSource + Sync + Send + 'static,
-    {
-        Self::new(notes, PhraseType::Sixteenth, sound_gen)
+    fn sixteenth(notes: &str, instrument: &Arc<Instrument>) -> Self {
+        Self::new(notes, PhraseType::Sixteenth, instrument.clone())
     }
 
     fn len(&self) -> usize {
-        self.notes.len() * self.phrase_type.mult()
+        self.steps.len() * self.phrase_type.mult()
     }
 
-    fn note(&self, mut idx: usize) -> Option<(i32, RawSource)> {
+    fn note<T, F>(&self, idx: usize, step_time: f32, mut play: F) -> Option<(i32, T)>
+    where
+        F: FnMut(&Arc<Instrument>, Note, f32) -> T,
+    {
         if !self.phrase_type.in_phrase(idx) {
             return None;
         }
 
-        idx = idx / self.phrase_type.mult();
-        if idx > self.notes.len() {
+        let idx = idx / self.phrase_type.mult();
+        let Some(&Step::Note {
+            voltage,
+            value,
+            velocity,
+        }) = self.steps.get(idx)
+        else {
             return None;
-        }
-
-        let note_byte = self.notes.as_bytes()[idx];
-        if let Some(note) = match note_byte as char {
-            'c' => Some(0),
-            'd' => Some(2),
-            'e' => Some(4),
-            'f' => Some(5),
-            'g' => Some(7),
-            'a' => Some(9),
-            'b' => Some(11),
-            _ => None,
-        } {
-            let voltage = note as f32 / 120.;
-            let frequency = frequency_per_volt(voltage + 0.2);
-            Some((note, (self.sound_gen)(frequency)))
-        } else if note_byte >= '0' as u8 && note_byte <= '9' as u8 {
-            let byte = note_byte - '0' as u8;
-            let voltage = byte as f32 / 120.;
-            let frequency = frequency_per_volt(voltage);
-            Some((byte as i32, (self.sound_gen)(frequency)))
-        } else {
-            None
-        }
+        };
+
+        let ties = self.steps[idx + 1..]
+            .iter()
+            .take_while(|step| matches!(step, Step::Tie))
+            .count();
+        let note = Note {
+            frequency: frequency_per_volt(voltage),
+            sustain: (ties * self.phrase_type.mult()) as f32 * step_time,
+        };
+
+        Some((value, play(&self.instrument, note, velocity)))
     }
 }
 
-fn square_horn(frequency: f32) -> RawSource {
-    Vca::new(
-        Vco::new(
-            Vcf::new(
-                SquareWave::new(frequency as f32).as_raw(),
-                frequency / 4.,
-                1.0,
-            ),
-            frequency / 2.,
-            Envelope::new(0.3, 0.1, 0.05, 0.1),
-        ),
-        Envelope::new(0.3, 0.05, 0.05, 0.2),
-    )
-    .as_raw()
-}
-
-fn kick(frequency: f32) -> RawSource {
-    let kick_env = Envelope::new(1.0, 0.001, 0.1, 0.3);
-    let freq_env = Envelope::new(0.02, 0.0, 0.0, 0.2);
-    let vco = Vco::new(TriangleWave::new(frequency), frequency, freq_env);
-    let osc = Attenuator::new(vco, 2.0);
-    let vca = Vca::new(osc, kick_env);
-
-    vca.as_raw()
-}
-
-fn snare(_frequency: f32) -> RawSource {
-    let snare_env = Envelope::new(0.2, 0.001, 0.0, 0.3);
-    let osc = NoiseLFSR::new(20000.);
-    let vca = Vca::new(osc, snare_env);
-    vca.as_raw()
-}
-
-// TODO: Convert all of these to voltage for easier drum type selection
-fn drum(frequency: f32) -> RawSource {
-    if frequency < 100. {
-        kick(frequency)
+/// Source of a note as a phrase plays it, quieter at velocities under 1.0
+pub fn play_note(instrument: &Instrument, note: Note, velocity: f32) -> RawSource {
+    let source = instrument.play(note);
+    if velocity < 1.0 {
+        Attenuator::new(source, velocity).into_raw()
     } else {
-        snare(frequency)
+        source
     }
 }
 
-fn supersaw(frequency: f32) -> RawSource {
-    Vca::new(
-        Vco::new(
-            Vcf::new(SuperSaw::new(frequency as f32), frequency / 2., 1.0),
-            frequency / 2.,
-            Envelope::new(0.3, 0.1, 0.05, 0.2),
-        ),
-        Envelope::new(0.3, 0.05, 0.05, 0.2),
+pub fn mary_song() -> Song {
+    let drum = Arc::new(instrument::drum());
+    let square_horn = Arc::new(instrument::square_horn());
+    let supersaw = Arc::new(instrument::supersaw());
+    let warble = Arc::new(instrument::warble());
+
+    Song::new(vec![
+        // drums
+        Track {
+            chains: vec![
+                Chain {
+                    phrases: vec![Phrase::sixteenth("009_90_0_0_0009_", &drum)],
+                },
+                Chain {
+                    phrases: vec![
+                        Phrase::quarter("1231", &drum),
+                        Phrase::sixteenth("11__22__33__11__", &drum),
+                    ],
+                },
+            ],
+        },
+        // melody
+        Track {
+            chains: vec![
+                Chain {
+                    phrases: vec![
+                        Phrase::eigth("edcdeee_", &square_horn),
+                        Phrase::eigth("ddd_cgg_", &square_horn),
+                        Phrase::eigth("edcdeee_", &square_horn),
+                        Phrase::eigth("ddedc___", &square_horn),
+                    ],
+                },
+                Chain {
+                    phrases: vec![
+                        Phrase::eigth("edcdeee_", &supersaw),
+                        Phrase::eigth("ddd_cgg_", &supersaw),
+                        Phrase::eigth("edcdeee_", &supersaw),
+                        Phrase::eigth("ddedc___", &supersaw),
+                    ],
+                },
+            ],
+        },
+        // drums
+        Track {
+            chains: vec![
+                Chain { phrases: vec![] },
+                Chain {
+                    phrases: vec![
+                        Phrase::quarter("1231", &drum),
+                        Phrase::sixteenth("11__22__33__11__", &drum),
+                    ],
+                },
+            ],
+        },
+        // melody
+        Track {
+            chains: vec![
+                Chain { phrases: vec![] },
+                Chain {
+                    phrases: vec![
+                        Phrase::eigth("edcdeee_", &warble),
+                        Phrase::eigth("ddd_cgg_", &warble),
+                        Phrase::eigth("edcdeee_", &warble),
+                        Phrase::eigth("ddedc___", &warble),
+                    ],
+                },
+            ],
+        },
+    ])
+    .with_sends(
+        1,
+        Sends {
+            reverb: 0.3,
+            ..default()
+        },
     )
-    .as_raw()
-}
-
-fn warble(frequency: f32) -> RawSource {
-    Vca::new(
-        Vco::new(
-            RampWave::new(frequency),
-            frequency,
-            Attenuator::new(TriangleWave::new(40.), 0.2),
-        ),
-        Envelope::new(0.3, 0.01, 0.05, 0.25),
+    .with_sends(
+        3,
+        Sends {
+            chorus: 0.5,
+            ..default()
+        },
     )
-    .as_raw()
-}
-
-pub fn mary_song() -> Song {
-    Song {
-        tracks: vec![
-            // drums
-            Track {
-                chains: vec![
-                    Chain {
-                        phrases: vec![Phrase::sixteenth("009_90_0_0_0009_", drum)],
-                    },
-                    Chain {
-                        phrases: vec![
-                            Phrase::quarter("1231", drum),
-                            Phrase::sixteenth("11__22__33__11__", drum),
-                        ],
-                    },
-                ],
-            },
-            // melody
-            Track {
-                chains: vec![
-                    Chain {
-                        phrases: vec![
-                            Phrase::eigth("edcdeee_", square_horn),
-                            Phrase::eigth("ddd_cgg_", square_horn),
-                            Phrase::eigth("edcdeee_", square_horn),
-                            Phrase::eigth("ddedc___", square_horn),
-                        ],
-                    },
-                    Chain {
-                        phrases: vec![
-                            Phrase::eigth("edcdeee_", supersaw),
-                            Phrase::eigth("ddd_cgg_", supersaw),
-                            Phrase::eigth("edcdeee_", supersaw),
-                            Phrase::eigth("ddedc___", supersaw),
-                        ],
-                    },
-                ],
-            },
-            // drums
-            Track {
-                chains: vec![
-                    Chain { phrases: vec![] },
-                    Chain {
-                        phrases: vec![
-                            Phrase::quarter("1231", drum),
-                            Phrase::sixteenth("11__22__33__11__", drum),
-                        ],
-                    },
-                ],
-            },
-            // melody
-            Track {
-                chains: vec![
-                    Chain { phrases: vec![] },
-                    Chain {
-                        phrases: vec![
-                            Phrase::eigth("edcdeee_", warble),
-                            Phrase::eigth("ddd_cgg_", warble),
-                            Phrase::eigth("edcdeee_", warble),
-                            Phrase::eigth("ddedc___", warble),
-                        ],
-                    },
-                ],
-            },
-        ],
-    }
 }
 
 pub fn other_song() -> Song {
-    Song {
-        tracks: vec![
-            // drums
-            Track {
-                chains: vec![Chain {
-                    phrases: vec![Phrase::eigth("09090909", drum)],
-                }],
-            },
-            // melody
-            Track {
-                chains: vec![Chain {
-                    phrases: vec![
-                        Phrase::sixteenth("cde___e_c_____f_", supersaw),
-                        Phrase::sixteenth("__f___e_d___c___", supersaw),
-                    ],
-                }],
-            },
-        ],
-    }
+    let drum = Arc::new(instrument::drum());
+    let supersaw = Arc::new(instrument::supersaw());
+
+    Song::new(vec![
+        // drums
+        Track {
+            chains: vec![Chain {
+                phrases: vec![Phrase::eigth("09090909", &drum)],
+            }],
+        },
+        // melody
+        Track {
+            chains: vec![Chain {
+                phrases: vec![
+                    Phrase::sixteenth("cde___e_c_____f_", &supersaw),
+                    Phrase::sixteenth("__f___e_d___c___", &supersaw),
+                ],
+            }],
+        },
+    ])
 }
 
 pub fn techno() -> Song {
-    Song {
-        tracks: vec![
-            // drums
-            Track {
-                chains: vec![
-                    Chain {
-                        phrases: vec![Phrase::sixteenth("0__10___0__10___", drum)],
-                    },
-                    Chain {
-                        phrases: vec![Phrase::sixteenth("0__10_8_0__10_9_", drum)],
-                    },
+    let drum = Arc::new(instrument::drum());
+    let supersaw = Arc::new(instrument::supersaw());
+    let square_horn = Arc::new(instrument::square_horn());
+
+    Song::new(vec![
+        // drums
+        Track {
+            chains: vec![
+                Chain {
+                    phrases: vec![Phrase::sixteenth("0__10___0__10___", &drum)],
+                },
+                Chain {
+                    phrases: vec![Phrase::sixteenth("0__10_8_0__10_9_", &drum)],
+                },
+            ],
+        },
+        // melody
+        Track {
+            chains: vec![Chain {
+                phrases: vec![
+                    Phrase::sixteenth("cde___e_c_____f_", &supersaw),
+                    Phrase::sixteenth("__f___e_d___c___", &supersaw),
                 ],
-            },
-            // melody
-            Track {
-                chains: vec![Chain {
+            }],
+        },
+        // Top drums
+        Track {
+            chains: vec![
+                Chain { phrases: vec![] },
+                Chain {
+                    phrases: vec![Phrase::sixteenth("_4___56__4___56_", &drum)],
+                },
+            ],
+        },
+        // Counter melody
+        Track {
+            chains: vec![
+                Chain { phrases: vec![] },
+                Chain {
                     phrases: vec![
-                        Phrase::sixteenth("cde___e_c_____f_", supersaw),
-                        Phrase::sixteenth("__f___e_d___c___", supersaw),
+                        Phrase::sixteenth("gab___b_g_____f_", &square_horn),
+                        Phrase::sixteenth("__f___a_b___g___", &square_horn),
                     ],
-                }],
-            },
-            // Top drums
-            Track {
-                chains: vec![
-                    Chain { phrases: vec![] },
-                    Chain {
-                        phrases: vec![Phrase::sixteenth("_4___56__4___56_", drum)],
-                    },
-                ],
-            },
-            // Counter melody
-            Track {
-                chains: vec![
-                    Chain { phrases: vec![] },
-                    Chain {
-                        phrases: vec![
-                            Phrase::sixteenth("gab___b_g_____f_", square_horn),
-                            Phrase::sixteenth("__f___a_b___g___", square_horn),
-                        ],
-                    },
-                ],
-            },
-        ],
+                },
+            ],
+        },
+    ])
+    .with_sends(
+        1,
+        Sends {
+            reverb: 0.25,
+            ..default()
+        },
+    )
+    .with_sends(
+        3,
+        Sends {
+            delay: 0.35,
+            ..default()
+        },
+    )
+}
+
+/// Look up a song by name, for headless rendering.
+pub fn song_by_name(name: &str) -> Option<Song> {
+    match name {
+        "mary" => Some(mary_song()),
+        "other" => Some(other_song()),
+        "techno" => Some(techno()),
+        _ => None,
     }
 }
@@ -0,0 +1,335 @@
+//! `.bjsong` song files, in [RON](https://github.com/ron-rs/ron).
+//!
+//! ```ron
+//! (
+//!     bpm: 128.0,
+//!     swing: 0.0,
+//!     chain_bpm: [(1, 136.0)],
+//!     // Added to the `Instruments` for this song only
+//!     instruments: {
+//!         "bass": Patch((waveform: Saw, amp: (amplitude: 0.4, attack: 0.01, hold: 0.1, release: 0.1))),
+//!     },
+//!     tracks: [
+//!         // Each track is a list of chains, each chain a list of phrases
+//!         (chains: [
+//!             [(notes: "0__10___0__10___", type: Sixteenth, instrument: "drum")],
+//!             [(notes: "cde_", type: Eigth, instrument: "bass", overrides: (cutoff: 2.0))],
+//!         ], gain: 0.8, sends: (delay: 0.3, reverb: 0.2)),
+//!     ],
+//! )
+//! ```
+//!
+//! Notes are written as in `song::Phrase`.
+//! Instruments are looked up by name in `Instruments`, see `instrument` for their parameters.
+//! `Sampler` instruments play files from `assets/samples`, eg:
+//! `"kick": Sampler((sample: "samples/kick.wav", gain: 0.6))`.
+
+use super::audio::mixer::Sends;
+use super::instrument::{Instrument, Instruments, Overrides};
+use super::song::{Chain, Phrase, PhraseType, Song, Track};
+use bevy::asset::{AssetLoader, LoadContext, LoadedAsset};
+use bevy::prelude::*;
+use bevy::utils::{BoxedFuture, HashMap};
+use ron::extensions::Extensions;
+use serde::Deserialize;
+use std::fmt;
+use std::sync::Arc;
+
+#[derive(Deserialize)]
+struct SongFile {
+    #[serde(default)]
+    bpm: Option<f32>,
+    #[serde(default)]
+    swing: f32,
+    /// (chain, bpm) tempo changes
+    #[serde(default)]
+    chain_bpm: Vec<(usize, f32)>,
+    #[serde(default)]
+    instruments: HashMap<String, Instrument>,
+    tracks: Vec<TrackFile>,
+}
+
+#[derive(Deserialize)]
+struct TrackFile {
+    chains: Vec<Vec<PhraseFile>>,
+    #[serde(default = "default_gain")]
+    gain: f32,
+    #[serde(default)]
+    sends: Sends,
+}
+
+fn default_gain() -> f32 {
+    1.0
+}
+
+#[derive(Deserialize)]
+struct PhraseFile {
+    notes: String,
+    #[serde(rename = "type")]
+    phrase_type: PhraseType,
+    instrument: String,
+    #[serde(default)]
+    overrides: Option<Overrides>,
+}
+
+#[derive(Debug)]
+pub enum SongFileError {
+    Parse(ron::error::SpannedError),
+    UnknownInstrument(String),
+    /// Name of the instrument, and what's wrong with it
+    InvalidInstrument(String, String),
+    /// A bpm that isn't a positive number
+    InvalidTempo(f32),
+}
+
+impl fmt::Display for SongFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SongFileError::Parse(err) => write!(f, "invalid song file: {}", err),
+            SongFileError::UnknownInstrument(name) => write!(f, "unknown instrument {}", name),
+            SongFileError::InvalidInstrument(name, reason) => {
+                write!(f, "invalid instrument {}: {}", name, reason)
+            }
+            SongFileError::InvalidTempo(bpm) => write!(f, "invalid tempo {} bpm", bpm),
+        }
+    }
+}
+
+impl std::error::Error for SongFileError {}
+
+/// Parse the contents of a `.bjsong` file, playing `instruments` and any it defines.
+pub fn parse_song(bytes: &[u8], instruments: &Instruments) -> Result<Song, SongFileError> {
+    let file: SongFile = ron::Options::default()
+        .with_default_extension(Extensions::IMPLICIT_SOME)
+        .from_bytes(bytes)
+        .map_err(SongFileError::Parse)?;
+
+    let chain_tempos = file.chain_bpm.iter().map(|(_, bpm)| bpm);
+    for &bpm in file.bpm.iter().chain(chain_tempos) {
+        if !(bpm.is_finite() && bpm > 0.) {
+            return Err(SongFileError::InvalidTempo(bpm));
+        }
+    }
+
+    // Kept to this song, rather than added to the shared `Instruments`
+    let mut own = HashMap::default();
+    for (name, mut instrument) in file.instruments {
+        if let Err(reason) = instrument.validate() {
+            return Err(SongFileError::InvalidInstrument(name, reason));
+        }
+        instrument.bind_samples(instruments.samples());
+        own.insert(name, Arc::new(instrument));
+    }
+
+    let mut tracks = Vec::new();
+    let mut mix = Vec::new();
+    for track in file.tracks {
+        mix.push((track.gain, track.sends));
+        let mut chains = Vec::new();
+        for chain in track.chains {
+            let mut phrases = Vec::new();
+            for phrase in chain {
+                let found = own.get(&phrase.instrument).cloned();
+                let Some(mut instrument) = found.or_else(|| instruments.get(&phrase.instrument))
+                else {
+                    return Err(SongFileError::UnknownInstrument(phrase.instrument));
+                };
+                if let Some(overrides) = &phrase.overrides {
+                    instrument = Arc::new(instrument.with_overrides(overrides));
+                }
+                phrases.push(Phrase::new(&phrase.notes, phrase.phrase_type, instrument));
+            }
+            chains.push(Chain::new(phrases));
+        }
+        tracks.push(Track::new(chains));
+    }
+
+    let mut song = Song::new(tracks).with_swing(file.swing);
+    if let Some(bpm) = file.bpm {
+        song = song.with_bpm(bpm);
+    }
+    for (chain, bpm) in file.chain_bpm {
+        song = song.with_chain_bpm(chain, bpm);
+    }
+    for (track, (gain, sends)) in mix.into_iter().enumerate() {
+        song = song.with_gain(track, gain).with_sends(track, sends);
+    }
+    Ok(song)
+}
+
+/// Loads songs with the shared `Instruments`, including any added after the loader.
+pub struct SongLoader {
+    instruments: Instruments,
+}
+
+impl FromWorld for SongLoader {
+    fn from_world(world: &mut World) -> Self {
+        SongLoader {
+            instruments: world
+                .get_resource_or_insert_with(Instruments::default)
+                .clone(),
+        }
+    }
+}
+
+impl AssetLoader for SongLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let song = parse_song(bytes, &self.instruments)?;
+            load_context.set_default_asset(LoadedAsset::new(song));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["bjsong"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(song: &str) -> Result<Song, SongFileError> {
+        parse_song(song.as_bytes(), &Instruments::default())
+    }
+
+    #[test]
+    fn unenveloped_loop_is_rejected() {
+        let song = r#"(
+            instruments: {
+                "pad": Sampler((sample: "samples/hat.wav", loop_points: (100, 200))),
+            },
+            tracks: [(chains: [[(notes: "c", type: Quarter, instrument: "pad")]])],
+        )"#;
+        assert!(matches!(
+            parse(song),
+            Err(SongFileError::InvalidInstrument(name, _)) if name == "pad"
+        ));
+    }
+
+    #[test]
+    fn enveloped_loop_is_accepted() {
+        let song = r#"(
+            instruments: {
+                "pad": Sampler((
+                    sample: "samples/hat.wav",
+                    loop_points: (100, 200),
+                    amp: (amplitude: 0.5, attack: 0.01, hold: 0.1, release: 0.1),
+                )),
+            },
+            tracks: [(chains: [[(notes: "c", type: Quarter, instrument: "pad")]])],
+        )"#;
+        assert!(parse(song).is_ok());
+    }
+
+    #[test]
+    fn endless_graph_is_rejected() {
+        let song = |ends_with: &str| {
+            format!(
+                r#"(
+                    instruments: {{
+                        "graph": Graph((
+                            modules: [
+                                Oscillator(waveform: Saw),
+                                Envelope((amplitude: 0.5, attack: 0.01, hold: 0.1, release: 0.1)),
+                                Vca(gain: 0.0),
+                            ],
+                            connections: [(0, 2, Audio, 1.0), (1, 2, Gain, 1.0)],
+                            output: 2,
+                            {}
+                        )),
+                    }},
+                    tracks: [(chains: [[(notes: "c", type: Quarter, instrument: "graph")]])],
+                )"#,
+                ends_with
+            )
+        };
+        assert!(parse(&song("ends_with: 1,")).is_ok());
+        for ends_with in ["", "ends_with: 0,", "ends_with: 3,"] {
+            assert!(
+                matches!(
+                    parse(&song(ends_with)),
+                    Err(SongFileError::InvalidInstrument(name, _)) if name == "graph"
+                ),
+                "{}",
+                ends_with
+            );
+        }
+    }
+
+    #[test]
+    fn long_delay_is_rejected() {
+        let song = |seconds: f32| {
+            format!(
+                r#"(
+                    instruments: {{
+                        "echo": Patch((
+                            waveform: Saw,
+                            amp: (amplitude: 0.4, attack: 0.01, hold: 0.1, release: 0.1),
+                            effects: [Delay(seconds: {:?}, feedback: 0.5, mix: 0.5)],
+                        )),
+                    }},
+                    tracks: [(chains: [[(notes: "c", type: Quarter, instrument: "echo")]])],
+                )"#,
+                seconds
+            )
+        };
+        assert!(parse(&song(0.3)).is_ok());
+        for seconds in [0.0, -1.0, 1e9] {
+            assert!(
+                matches!(
+                    parse(&song(seconds)),
+                    Err(SongFileError::InvalidInstrument(name, _)) if name == "echo"
+                ),
+                "{}",
+                seconds
+            );
+        }
+    }
+
+    #[test]
+    fn fm_operator_count_is_checked() {
+        let song = |operators: &str| {
+            format!(
+                r#"(
+                    instruments: {{ "fm": Fm((operators: [{}])) }},
+                    tracks: [(chains: [[(notes: "c", type: Quarter, instrument: "fm")]])],
+                )"#,
+                operators
+            )
+        };
+        let operator = "(ratio: 1.0, index: 1.0, envelope: (amplitude: 1.0, attack: 0.0, hold: 0.1, release: 0.1))";
+        for count in 0..=5 {
+            let operators = vec![operator; count].join(", ");
+            let valid = parse(&song(&operators)).is_ok();
+            assert_eq!(valid, (2..=4).contains(&count), "{} operators", count);
+        }
+    }
+
+    #[test]
+    fn tempo_must_be_positive() {
+        let song = |tempo: &str| {
+            format!(
+                r#"(
+                    {}
+                    tracks: [(chains: [[(notes: "c", type: Quarter, instrument: "drum")]])],
+                )"#,
+                tempo
+            )
+        };
+        assert!(parse(&song("bpm: 90.0,")).is_ok());
+        assert!(matches!(
+            parse(&song("bpm: 0.0,")),
+            Err(SongFileError::InvalidTempo(_))
+        ));
+        assert!(matches!(
+            parse(&song("chain_bpm: [(1, -120.0)],")),
+            Err(SongFileError::InvalidTempo(_))
+        ));
+    }
+}
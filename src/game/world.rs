@@ -1,6 +1,10 @@
 use super::animation::{Animated, AnimationFrame};
-use super::assets::Sprites;
-use super::audio::Audio;
+use super::assets::{Songs, Sprites};
+use super::audio::analysis::{Analysis, SPECTRUM_BANDS};
+use super::audio::audio_output::AudioOutput;
+use super::audio::effects::Delay;
+use super::audio::mixer::{Bus, Mixer};
+use super::audio::sequencer::{Sequencer, SequencerHandle, SongEvent};
 use super::cannon::{spawn_cannon, Cannon};
 use super::enemy::{Enemy, EnemyAnimations, EnemyKilledEvent, EnemyType};
 use super::player::{OnBeat, Player, PlayerAnimations};
@@ -18,42 +22,26 @@ impl bevy::app::Plugin for WorldPlugin {
             //.insert_resource(techno())
             .add_event::<EnemyKilledEvent>()
             .add_system(world_startup.in_schedule(OnEnter(GameState::Playing)))
+            .add_system(stop_song.in_schedule(OnExit(GameState::Playing)))
             .add_system(world_teardown.in_schedule(OnExit(GameState::GameOver)))
+            .add_system(reset_camera.in_schedule(OnExit(GameState::Playing)))
             .add_systems(
                 (
                     spawn_system,
                     move_system,
                     enemy_spawn_system,
                     song_progression_system,
+                    song_reload_system,
+                    delay_sync_system,
                     transform_world_system.after(spawn_system),
+                    floor_pulse_system,
+                    screen_shake_system,
                 )
                     .in_set(OnUpdate(GameState::Playing)),
             );
     }
 }
 
-// 120 BPM, 60 seconds/min, 4/beat (16th notes)
-const BPM_TIMER_TIME: f32 = 0.125;
-
-#[derive(Resource)]
-struct SongTimer {
-    timer: Timer,
-    idx: usize,
-    chain: usize,
-    next_chain: bool,
-}
-
-impl Default for SongTimer {
-    fn default() -> Self {
-        SongTimer {
-            timer: Timer::from_seconds(1.0, TimerMode::Once),
-            idx: 0,
-            chain: 0,
-            next_chain: false,
-        }
-    }
-}
-
 #[derive(Component)]
 struct Background;
 
@@ -89,6 +77,11 @@ impl World {
     fn in_bounds(&self, pos: &Vec2) -> bool {
         pos.x >= 0. && pos.y >= 0. && pos.x <= self.size.x * 16. && pos.y <= self.size.y * 16.
     }
+
+    /// Stereo position of a sound at `pos`, -1.0 at the left wall to 1.0 at the right
+    pub fn pan(&self, pos: Vec2) -> f32 {
+        (pos.x / (self.size.x * 16.) * 2. - 1.).clamp(-1., 1.)
+    }
 }
 
 #[derive(Component)]
@@ -105,8 +98,14 @@ fn world_startup(
     asset_server: Res<AssetServer>,
     mut texture_atlases: ResMut<Assets<TextureAtlas>>,
     mut sprites: Res<Sprites>,
+    song: Res<Song>,
+    audio_output: Res<AudioOutput>,
 ) {
-    commands.insert_resource(SongTimer::default());
+    // Notes are started on the audio thread, steps come back as `SongEvent`s.
+    let (sequencer, sequencer_handle) = Sequencer::new(song.clone());
+    audio_output.mixer.play_sequence(sequencer);
+    mix_song(&song, 0, &audio_output.mixer);
+    commands.insert_resource(sequencer_handle);
 
     commands.spawn((
         SpriteSheetBundle {
@@ -143,34 +142,28 @@ fn world_startup(
 
     spawn_world_grid(&mut commands, sprites.floor.clone(), sprites.wall.clone());
 
-    // Left
-    spawn_cannon(
-        Cannon::new(12, 1, Vec2::new(1., 0.)),
-        &mut commands,
-        Vec2::new(16., 16.),
-        &sprites,
-    );
-    // Bottom
-    spawn_cannon(
-        Cannon::new(12, 0, Vec2::new(0., 1.)),
-        &mut commands,
-        Vec2::new(32., 16.),
-        &sprites,
-    );
-    // Right
-    spawn_cannon(
-        Cannon::new(12, 3, Vec2::new(-1., 0.)),
-        &mut commands,
-        Vec2::new(24. * 16., 16.),
-        &sprites,
-    );
-    // Top
-    spawn_cannon(
-        Cannon::new(12, 2, Vec2::new(0., -1.)),
-        &mut commands,
-        Vec2::new((24. - 12.) * 16., 16. * 16.),
-        &sprites,
-    );
+    let cannons = [
+        // Left
+        (Cannon::new(12, 1, Vec2::new(1., 0.)), Vec2::new(16., 16.)),
+        // Bottom
+        (Cannon::new(12, 0, Vec2::new(0., 1.)), Vec2::new(32., 16.)),
+        // Right
+        (
+            Cannon::new(12, 3, Vec2::new(-1., 0.)),
+            Vec2::new(24. * 16., 16.),
+        ),
+        // Top
+        (
+            Cannon::new(12, 2, Vec2::new(0., -1.)),
+            Vec2::new((24. - 12.) * 16., 16. * 16.),
+        ),
+    ];
+    for (cannon, position) in cannons {
+        audio_output
+            .mixer
+            .set_pan(Bus::Track(cannon.track), cannon.pan());
+        spawn_cannon(cannon, &mut commands, position, &sprites);
+    }
 }
 
 fn spawn_world_grid(
@@ -228,7 +221,7 @@ fn spawn_world_grid(
 }
 
 fn song_progression_system(
-    mut song_timer: ResMut<SongTimer>,
+    sequencer: Res<SequencerHandle>,
     mut event_reader: EventReader<EnemyKilledEvent>,
     mut world_query: Query<&mut World>,
 ) {
@@ -240,33 +233,93 @@ fn song_progression_system(
 
         let killed = world.enemy_killed;
         if killed % 2 == 0 {
-            song_timer.next_chain = true;
+            sequencer.next_chain();
         }
     }
 }
 
+/// Length of the song's delay, a dotted eighth
+const DELAY_SIXTEENTHS: f32 = 3.;
+
+/// The song's delay, in time with `chain`
+fn song_delay(song: &Song, chain: usize) -> Delay {
+    Delay::synced(DELAY_SIXTEENTHS, song.step_time(chain), 0.4, 1.0)
+}
+
+/// Set the mixer's track gains and effects up for `song`, playing `chain`.
+fn mix_song(song: &Song, chain: usize, mixer: &Mixer) {
+    mixer.set_delay(song_delay(song, chain));
+    // Only the first 4 tracks are played
+    for track in 0..4 {
+        mixer.set_gain(Bus::Track(track), song.gain(track));
+        mixer.set_sends(Bus::Track(track), song.sends(track));
+    }
+}
+
+/// Hot reload the playing song when its file changes.
+fn song_reload_system(
+    mut commands: Commands,
+    mut events: EventReader<AssetEvent<Song>>,
+    songs: Res<Songs>,
+    song_assets: Res<Assets<Song>>,
+    sequencer: Res<SequencerHandle>,
+    audio_output: Res<AudioOutput>,
+) {
+    for event in events.iter() {
+        if let AssetEvent::Modified { handle } = event {
+            if *handle != songs.current {
+                continue;
+            }
+            if let Some(song) = song_assets.get(handle) {
+                info!("Reloaded song");
+                commands.insert_resource(song.clone());
+                sequencer.set_song(song.clone());
+                mix_song(song, sequencer.chain(), &audio_output.mixer);
+            }
+        }
+    }
+}
+
+/// Keep the delay in time when a chain changes the tempo.
+fn delay_sync_system(
+    song: Res<Song>,
+    sequencer: Res<SequencerHandle>,
+    audio_output: Res<AudioOutput>,
+    mut chain: Local<usize>,
+) {
+    let playing = sequencer.chain();
+    if playing == *chain {
+        return;
+    }
+    // Replacing the delay cuts its echoes, so only when the tempo actually changes
+    if song.step_time(playing) != song.step_time(*chain) {
+        audio_output.mixer.set_delay(song_delay(&song, playing));
+    }
+    *chain = playing;
+}
+
 fn spawn_system(
     mut commands: Commands,
     sprites: Res<Sprites>,
-    mut song_timer: ResMut<SongTimer>,
-    song: Res<Song>,
-    time: Res<Time>,
-    audio: Res<Audio>,
+    sequencer: Res<SequencerHandle>,
     mut state: ResMut<NextState<GameState>>,
     mut on_beat: ResMut<OnBeat>,
     cannon_query: Query<(&Cannon, &WorldPosition)>,
 ) {
-    song_timer.timer.tick(time.delta());
+    for event in sequencer.events() {
+        let notes = match event {
+            SongEvent::Step { notes } => notes,
+            SongEvent::Finished => {
+                commands.insert_resource(EndState::Winner);
+                state.set(GameState::GameOver);
+                continue;
+            }
+        };
 
-    if song_timer.timer.finished() {
         on_beat.0 = false;
 
-        for (idx, maybe_note) in song
-            .note(song_timer.idx, song_timer.chain)
-            .into_iter()
-            .enumerate()
-        {
-            if let Some((note, source)) = maybe_note {
+        for (idx, maybe_note) in notes.into_iter().enumerate() {
+            if let Some(note) = maybe_note {
                 if idx == 0 {
                     on_beat.0 = true;
                 }
@@ -287,25 +340,8 @@ fn spawn_system(
                         WorldPosition::new(spawn_pos, 1.),
                     ));
                 }
-                audio.play(source);
-            }
-        }
-
-        song_timer.idx += 1;
-        if song_timer.idx >= song.len(song_timer.chain) {
-            song_timer.idx = 0;
-            if song_timer.next_chain {
-                song_timer.chain += 1;
-                song_timer.next_chain = false;
-
-                if song_timer.chain >= song.max_chains() {
-                    commands.insert_resource(EndState::Winner);
-                    state.set(GameState::GameOver);
-                }
             }
         }
-
-        song_timer.timer = Timer::from_seconds(BPM_TIMER_TIME, TimerMode::Once);
     }
 }
 
@@ -380,6 +416,50 @@ fn transform_world_system(mut query: Query<(&mut Transform, &WorldPosition)>) {
     }
 }
 
+/// Top of the spectrum the floor pulses to, where the kick drum is
+const BASS_FREQUENCY: f32 = 200.;
+
+/// Floor brightens with the bass of the mix
+fn floor_pulse_system(
+    analysis: Res<Analysis>,
+    mut query: Query<&mut TextureAtlasSprite, With<Background>>,
+) {
+    let bass = (0..SPECTRUM_BANDS)
+        .take_while(|&band| Analysis::band_frequency(band) < BASS_FREQUENCY)
+        .fold(0.0_f32, |bass, band| bass.max(analysis.spectrum[band]));
+    let brightness = 0.85 + 0.15 * (bass * 2.).min(1.);
+    for mut sprite in &mut query {
+        sprite.color = Color::rgb(brightness, brightness, brightness);
+    }
+}
+
+/// Drum peaks above this shake the screen
+const SHAKE_THRESHOLD: f32 = 0.25;
+
+/// Screen shakes on loud hits of the drum track (track 0)
+fn screen_shake_system(
+    analysis: Res<Analysis>,
+    mut camera_query: Query<&mut Transform, With<Camera>>,
+) {
+    let shake = (analysis.track(0).peak - SHAKE_THRESHOLD).max(0.) * 12.;
+    let mut rng = rand::thread_rng();
+    for mut transform in &mut camera_query {
+        let offset = Vec2::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0)) * shake;
+        transform.translation = offset.extend(transform.translation.z);
+    }
+}
+
+/// Undo any screen shake left over when play stops
+fn reset_camera(mut camera_query: Query<&mut Transform, With<Camera>>) {
+    for mut transform in &mut camera_query {
+        transform.translation = Vec3::new(0., 0., transform.translation.z);
+    }
+}
+
+fn stop_song(sequencer: Res<SequencerHandle>) {
+    sequencer.stop();
+}
+
 fn world_teardown(
     mut commands: Commands,
     query: Query<
@@ -394,7 +474,7 @@ fn world_teardown(
         )>,
     >,
 ) {
-    commands.remove_resource::<SongTimer>();
+    commands.remove_resource::<SequencerHandle>();
     for entity in &query {
         commands.entity(entity).despawn_recursive();
     }
@@ -1,9 +1,45 @@
 use bevy::prelude::*;
 use bevy::DefaultPlugins;
+use game::audio::backend::Backend;
+use game::audio::voice::{StealMode, VoiceLimits};
 
 mod game;
 
 fn main() {
+    // Render audio to a WAV file instead of running the game, for headless machines.
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("--render") {
+        if let Err(err) = game::audio::audio_render::render_cli(&args[1..]) {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let mut audio = game::audio::AudioPlugin {
+        // Bullet hits pile up, cut the ones that have already died away
+        voices: VoiceLimits {
+            steal_mode: StealMode::Quietest,
+            ..default()
+        },
+        ..default()
+    };
+    let mut options = args.iter();
+    while let Some(option) = options.next() {
+        match option.as_str() {
+            "--no-audio" => audio.backend = Backend::Null,
+            // Record everything played to a WAV file
+            "--capture" => {
+                let Some(path) = options.next() else {
+                    eprintln!("usage: --capture <out.wav>");
+                    std::process::exit(1);
+                };
+                audio.backend = Backend::CaptureFile(path.into());
+            }
+            _ => {}
+        }
+    }
+
     let mut app = App::new();
     app.insert_resource(Msaa::Off)
         .insert_resource(ClearColor(Color::rgb(0.4, 0.4, 0.4)))
@@ -17,10 +53,15 @@ fn main() {
                     }),
                     ..default()
                 })
-                .set(ImagePlugin::default_nearest()),
+                .set(ImagePlugin::default_nearest())
+                .set(AssetPlugin {
+                    // Hot reload song files
+                    watch_for_changes: true,
+                    ..default()
+                }),
         );
 
-    app.add_plugin(game::Plugin);
+    app.add_plugin(game::Plugin { audio });
 
     /*
     if cfg!(debug_assertions) {